@@ -0,0 +1,611 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use crate::audio::decode_file;
+use crate::trainer::{TimingLog, linear_fit};
+
+/// Analysis window and hop in samples.
+const FRAME: usize = 256;
+const HOP: usize = 64;
+/// Onsets closer together than this are merged into the first one.
+const MIN_ONSET_GAP_SECS: f64 = 0.05;
+
+/// Mono samples decoded from an audio file.
+pub struct Audio {
+    pub samples: Vec<f32>,
+    pub sample_rate: u32,
+}
+
+/// Onset strength sampled every `hop_secs`, aligned to the attack time.
+pub struct Envelope {
+    pub values: Vec<f32>,
+    pub hop_secs: f64,
+    pub start_secs: f64,
+}
+
+/// Decodes any format rodio understands (WAV, FLAC, Ogg, MP3) down to mono.
+pub fn load_mono(path: &Path) -> Result<Audio, String> {
+    let (interleaved, channels, sample_rate) = decode_file(path)?;
+    let channels = channels as usize;
+    let samples = interleaved
+        .chunks(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect();
+    Ok(Audio {
+        samples,
+        sample_rate,
+    })
+}
+
+/// Rise in short-time RMS after a pre-emphasis filter, which favours the
+/// attack of percussive and plucked notes over sustained energy.
+pub fn onset_envelope(audio: &Audio) -> Envelope {
+    let mut emphasized = Vec::with_capacity(audio.samples.len());
+    let mut prev = 0.0f32;
+    for &x in &audio.samples {
+        emphasized.push(x - 0.97 * prev);
+        prev = x;
+    }
+    let frames = if emphasized.len() >= FRAME {
+        (emphasized.len() - FRAME) / HOP + 1
+    } else {
+        0
+    };
+    let mut rms_prev: Option<f32> = None;
+    let mut values = Vec::with_capacity(frames);
+    for i in 0..frames {
+        let window = &emphasized[i * HOP..i * HOP + FRAME];
+        let rms = (window.iter().map(|v| v * v).sum::<f32>() / FRAME as f32).sqrt();
+        values.push(rms_prev.map(|p| (rms - p).max(0.0)).unwrap_or(0.0));
+        rms_prev = Some(rms);
+    }
+    let rate = audio.sample_rate.max(1) as f64;
+    Envelope {
+        values,
+        hop_secs: HOP as f64 / rate,
+        // The rise shows up once the attack enters the end of the window.
+        start_secs: (FRAME - HOP / 2) as f64 / rate,
+    }
+}
+
+/// Onset times in seconds, picked as local maxima above an adaptive threshold.
+pub fn detect_onsets(env: &Envelope) -> Vec<f64> {
+    let values = &env.values;
+    let max = values.iter().copied().fold(0.0f32, f32::max);
+    if max <= 0.0 {
+        return Vec::new();
+    }
+    let floor = 0.1 * max;
+    let reach = ((0.1 / env.hop_secs) as usize).max(1);
+    let peak_reach = ((0.01 / env.hop_secs) as usize).max(1);
+    let min_gap = MIN_ONSET_GAP_SECS;
+    let mut onsets: Vec<f64> = Vec::new();
+    for i in 0..values.len() {
+        let v = values[i];
+        if v < floor {
+            continue;
+        }
+        let lo = i.saturating_sub(reach);
+        let hi = (i + reach + 1).min(values.len());
+        let local_mean = values[lo..hi].iter().sum::<f32>() / (hi - lo) as f32;
+        if v < 1.5 * local_mean {
+            continue;
+        }
+        let plo = i.saturating_sub(peak_reach);
+        let phi = (i + peak_reach + 1).min(values.len());
+        if values[plo..phi].iter().any(|&u| u > v) {
+            continue;
+        }
+        let t = env.start_secs + i as f64 * env.hop_secs;
+        if onsets.last().is_some_and(|&last| t - last < min_gap) {
+            continue;
+        }
+        onsets.push(t);
+    }
+    onsets
+}
+
+/// One detected note placed on the click grid.
+pub struct GridHit {
+    pub time_secs: f64,
+    pub bar: i64,
+    pub beat: i64,
+    pub tick: i64,
+    pub deviation_ms: f64,
+}
+
+/// Places onsets on a grid of `ticks_per_beat` ticks per beat starting at
+/// `offset_secs`, the first downbeat.
+pub fn place_on_grid(
+    onsets: &[f64],
+    bpm: u16,
+    beats_per_bar: u8,
+    ticks_per_beat: u8,
+    offset_secs: f64,
+) -> Vec<GridHit> {
+    let tick_secs = 60.0 / bpm.max(1) as f64 / ticks_per_beat.max(1) as f64;
+    let tpb = ticks_per_beat.max(1) as i64;
+    let ticks_per_bar = beats_per_bar.max(1) as i64 * tpb;
+    onsets
+        .iter()
+        .map(|&t| {
+            let k = ((t - offset_secs) / tick_secs).round() as i64;
+            let in_bar = k.rem_euclid(ticks_per_bar);
+            GridHit {
+                time_secs: t,
+                bar: k.div_euclid(ticks_per_bar) + 1,
+                beat: in_bar / tpb + 1,
+                tick: in_bar % tpb + 1,
+                deviation_ms: (t - offset_secs - k as f64 * tick_secs) * 1000.0,
+            }
+        })
+        .collect()
+}
+
+pub fn write_hits_csv(path: &Path, hits: &[GridHit]) -> std::io::Result<()> {
+    let mut file = File::create(path)?;
+    writeln!(file, "note,time_s,bar,beat,tick,deviation_ms")?;
+    for (i, h) in hits.iter().enumerate() {
+        writeln!(
+            file,
+            "{},{:.4},{},{},{},{:.3}",
+            i + 1,
+            h.time_secs,
+            h.bar,
+            h.beat,
+            h.tick,
+            h.deviation_ms
+        )?;
+    }
+    Ok(())
+}
+
+/// `metronome analyze`: timing of a recorded take against the click grid.
+pub fn run_analyze(
+    path: &Path,
+    bpm: u16,
+    beats_per_bar: u8,
+    ticks_per_beat: u8,
+    downbeat_ms: Option<i64>,
+    csv: Option<&Path>,
+) -> Result<(), String> {
+    let audio = load_mono(path)?;
+    let onsets = detect_onsets(&onset_envelope(&audio));
+    let Some(&first) = onsets.first() else {
+        return Err(format!("no notes detected in {}", path.display()));
+    };
+    let offset_secs = downbeat_ms.map(|ms| ms as f64 / 1000.0).unwrap_or(first);
+    let hits = place_on_grid(&onsets, bpm, beats_per_bar, ticks_per_beat, offset_secs);
+
+    println!(
+        "{}: {} notes, grid {} BPM, first downbeat at {:.3} s",
+        path.display(),
+        hits.len(),
+        bpm,
+        offset_secs
+    );
+    println!("    #     time  bar.beat.tick  deviation");
+    let mut log = TimingLog::default();
+    for (i, h) in hits.iter().enumerate() {
+        println!(
+            "{:>5} {:>8.3}  {:>4}.{}.{:<6} {:>+8.1} ms",
+            i + 1,
+            h.time_secs,
+            h.bar,
+            h.beat,
+            h.tick,
+            h.deviation_ms
+        );
+        log.record(h.deviation_ms);
+    }
+    println!();
+    print!("{}", log.report("Notes"));
+
+    if let Some(csv) = csv {
+        write_hits_csv(csv, &hits)
+            .map_err(|e| format!("could not write {}: {}", csv.display(), e))?;
+        println!("Saved {} notes to {}", hits.len(), csv.display());
+    }
+    Ok(())
+}
+
+/// Tempo range searched by `detect`.
+const DETECT_MIN_BPM: f64 = 40.0;
+const DETECT_MAX_BPM: f64 = 240.0;
+/// Centre of the tempo prior used to settle half/double-time ambiguity.
+const PRIOR_BPM: f64 = 120.0;
+
+/// Result of `estimate_tempo`.
+pub struct TempoEstimate {
+    pub bpm: f64,
+    /// Time of the first beat in seconds.
+    pub first_beat_secs: f64,
+    /// Most likely number of beats per bar (2, 3 or 4).
+    pub beats_per_bar: u8,
+    /// Time of the first beat that looks like a downbeat.
+    pub first_downbeat_secs: f64,
+    /// Normalised autocorrelation at the chosen period, 0.0..=1.0.
+    pub strength: f64,
+}
+
+/// Estimates tempo by autocorrelating the onset envelope, then finds the beat
+/// phase with a comb over the envelope and guesses the meter from how much
+/// the strongest beat position stands out.
+pub fn estimate_tempo(env: &Envelope) -> Option<TempoEstimate> {
+    // Coarser envelope keeps the autocorrelation cheap on long files.
+    const DECIMATE: usize = 4;
+    let values: Vec<f64> = env
+        .values
+        .chunks(DECIMATE)
+        .map(|c| c.iter().map(|&v| v as f64).sum::<f64>())
+        .collect();
+    let hop = env.hop_secs * DECIMATE as f64;
+    let mean = values.iter().sum::<f64>() / values.len().max(1) as f64;
+    // Smoothing widens the peaks, so a period that falls between two lags
+    // still correlates fully.
+    let centered: Vec<f64> = (0..values.len())
+        .map(|i| {
+            let prev = values[i.saturating_sub(1)];
+            let next = values.get(i + 1).copied().unwrap_or(values[i]);
+            0.25 * prev + 0.5 * values[i] + 0.25 * next - mean
+        })
+        .collect();
+
+    let min_lag = (60.0 / DETECT_MAX_BPM / hop).floor().max(1.0) as usize;
+    let max_lag = (60.0 / DETECT_MIN_BPM / hop).ceil() as usize;
+    if centered.len() <= max_lag * 2 {
+        return None;
+    }
+    let ac0: f64 = centered.iter().map(|v| v * v).sum();
+    if ac0 <= 0.0 {
+        return None;
+    }
+    let ac: Vec<f64> = (0..=max_lag + 1)
+        .map(|lag| {
+            centered[..centered.len() - lag]
+                .iter()
+                .zip(&centered[lag..])
+                .map(|(a, b)| a * b)
+                .sum::<f64>()
+                / ac0
+        })
+        .collect();
+
+    let mut best_lag = 0;
+    let mut best_score = f64::MIN;
+    for (lag, &value) in ac.iter().enumerate().take(max_lag + 1).skip(min_lag) {
+        let bpm = 60.0 / (lag as f64 * hop);
+        let octaves = (bpm / PRIOR_BPM).log2();
+        let score = value * (-0.5 * octaves * octaves).exp();
+        if score > best_score {
+            best_score = score;
+            best_lag = lag;
+        }
+    }
+    if best_lag == 0 {
+        return None;
+    }
+    // Every other beat of a steady pulse correlates as well as every beat,
+    // so the prior alone would halve fast tempos: take the pulse at half the
+    // period when it is nearly as regular.
+    let half = (best_lag as f64 / 2.0).round() as usize;
+    if half > min_lag {
+        let (lag, value) = (half - 1..=half + 1)
+            .map(|lag| (lag, ac[lag]))
+            .fold((0, f64::MIN), |best, c| if c.1 > best.1 { c } else { best });
+        if value >= 0.8 * ac[best_lag] {
+            best_lag = lag;
+        }
+    }
+    // Parabolic interpolation around the peak for a fractional period.
+    let (a, b, c) = (ac[best_lag - 1], ac[best_lag], ac[best_lag + 1]);
+    let denom = a - 2.0 * b + c;
+    let shift = if denom.abs() > f64::EPSILON {
+        (0.5 * (a - c) / denom).clamp(-0.5, 0.5)
+    } else {
+        0.0
+    };
+    let period = best_lag as f64 + shift;
+
+    // Beat phase: the offset whose comb of beats collects the most energy.
+    let beat_strength = |phase: f64, k: usize| -> f64 {
+        let idx = (phase + k as f64 * period).round() as usize;
+        let lo = idx.saturating_sub(2).min(values.len());
+        let hi = (idx + 3).min(values.len());
+        values[lo..hi].iter().copied().fold(0.0, f64::max)
+    };
+    let beats = ((values.len() as f64) / period) as usize;
+    let mut best_phase = 0usize;
+    let mut best_sum = f64::MIN;
+    for phase in 0..best_lag {
+        let sum: f64 = (0..beats).map(|k| beat_strength(phase as f64, k)).sum();
+        if sum > best_sum {
+            best_sum = sum;
+            best_phase = phase;
+        }
+    }
+
+    // Refine period and phase with a line through the peak nearest each beat,
+    // so a small period error does not pile up over a long file.
+    let mut period = period;
+    let mut phase = best_phase as f64;
+    let floor = 0.2 * values.iter().copied().fold(0.0, f64::max);
+    let reach = (period / 4.0) as usize;
+    let mut ks = Vec::new();
+    let mut peaks = Vec::new();
+    for k in 0..beats {
+        let center = (phase + k as f64 * period).round() as usize;
+        let lo = center.saturating_sub(reach).min(values.len());
+        let hi = (center + reach + 1).min(values.len());
+        if let Some((i, &v)) = values[lo..hi]
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+        {
+            if v > floor {
+                ks.push(k as f64);
+                peaks.push((lo + i) as f64);
+            }
+        }
+    }
+    if let Some((slope, intercept)) = linear_fit(&ks, &peaks) {
+        if (slope - period).abs() < period * 0.05 {
+            period = slope;
+            phase = intercept.max(0.0);
+        }
+    }
+
+    // Meter: the grouping whose loudest position stands out the most.
+    let mut beats_per_bar = 4u8;
+    let mut downbeat = 0usize;
+    let mut best_contrast = 0.0;
+    for m in [4u8, 3, 2] {
+        let mut sums = vec![0.0; m as usize];
+        for k in 0..beats {
+            sums[k % m as usize] += beat_strength(phase, k);
+        }
+        let total: f64 = sums.iter().sum();
+        if total <= 0.0 {
+            continue;
+        }
+        let (peak_pos, peak) =
+            sums.iter().copied().enumerate().fold(
+                (0, 0.0),
+                |best, (i, v)| if v > best.1 { (i, v) } else { best },
+            );
+        let contrast = peak / (total / m as f64);
+        // Require a clear margin before preferring a shorter bar.
+        if contrast > best_contrast * 1.05 {
+            best_contrast = contrast;
+            beats_per_bar = m;
+            downbeat = peak_pos;
+        }
+    }
+
+    // A decimated frame stands for the middle of the frames it summed.
+    let first_beat_secs = env.start_secs + phase * hop + (DECIMATE - 1) as f64 / 2.0 * env.hop_secs;
+    Some(TempoEstimate {
+        bpm: 60.0 / (period * hop),
+        first_beat_secs,
+        beats_per_bar,
+        first_downbeat_secs: first_beat_secs + downbeat as f64 * period * hop,
+        strength: ac[best_lag].clamp(0.0, 1.0),
+    })
+}
+
+/// `metronome detect`: prints the estimated tempo of an audio file.
+pub fn run_detect(path: &Path) -> Result<TempoEstimate, String> {
+    let audio = load_mono(path)?;
+    let estimate = estimate_tempo(&onset_envelope(&audio)).ok_or_else(|| {
+        format!(
+            "{} is too short or too quiet to detect a tempo",
+            path.display()
+        )
+    })?;
+    println!("{}:", path.display());
+    println!(
+        "  Tempo: {:.1} BPM (strength {:.0}%)",
+        estimate.bpm,
+        estimate.strength * 100.0
+    );
+    println!("  First beat: {:.3} s", estimate.first_beat_secs);
+    println!(
+        "  Meter (guess): {}/4, first downbeat at {:.3} s",
+        estimate.beats_per_bar, estimate.first_downbeat_secs
+    );
+    Ok(estimate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 44_100;
+
+    /// Short decaying 2 kHz blips starting at `times`.
+    fn clicks(times: &[f64], secs: f64) -> Audio {
+        let notes: Vec<(f64, f32)> = times.iter().map(|&t| (t, 1.0)).collect();
+        accented_clicks(&notes, secs)
+    }
+
+    /// Blips at the given times and levels.
+    fn accented_clicks(notes: &[(f64, f32)], secs: f64) -> Audio {
+        let mut samples = vec![0.0f32; (secs * RATE as f64) as usize];
+        for &(t, level) in notes {
+            let start = (t * RATE as f64).round() as usize;
+            for i in 0..(0.02 * RATE as f64) as usize {
+                let x = i as f32 / RATE as f32;
+                if let Some(s) = samples.get_mut(start + i) {
+                    *s += level * (std::f32::consts::TAU * 2000.0 * x).sin() * (-x / 0.004).exp();
+                }
+            }
+        }
+        Audio {
+            samples,
+            sample_rate: RATE,
+        }
+    }
+
+    fn assert_near(actual: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{} is not within {} of {}",
+            actual,
+            tolerance,
+            expected
+        );
+    }
+
+    #[test]
+    fn detects_every_click_on_time() {
+        let times: Vec<f64> = (0..8).map(|k| 0.25 + k as f64 * 0.5).collect();
+        let onsets = detect_onsets(&onset_envelope(&clicks(&times, 4.5)));
+        assert_eq!(onsets.len(), times.len());
+        for (onset, t) in onsets.iter().zip(&times) {
+            assert_near(*onset, *t, 0.003);
+        }
+    }
+
+    #[test]
+    fn exact_grid_has_no_deviation() {
+        let times: Vec<f64> = (0..8).map(|k| 0.25 + k as f64 * 0.5).collect();
+        let onsets = detect_onsets(&onset_envelope(&clicks(&times, 4.5)));
+        let hits = place_on_grid(&onsets, 120, 4, 1, 0.25);
+        let positions: Vec<(i64, i64)> = hits.iter().map(|h| (h.bar, h.beat)).collect();
+        assert_eq!(
+            positions,
+            [
+                (1, 1),
+                (1, 2),
+                (1, 3),
+                (1, 4),
+                (2, 1),
+                (2, 2),
+                (2, 3),
+                (2, 4)
+            ]
+        );
+        for h in &hits {
+            assert_near(h.deviation_ms, 0.0, 3.0);
+            assert_eq!(h.tick, 1);
+        }
+    }
+
+    #[test]
+    fn reports_early_and_late_notes() {
+        // Sixteenths at 100 BPM are 150 ms apart.
+        let offsets_ms = [0.0, 20.0, -15.0, 8.0, -40.0];
+        let times: Vec<f64> = offsets_ms
+            .iter()
+            .enumerate()
+            .map(|(k, ms)| 1.0 + k as f64 * 0.15 + ms / 1000.0)
+            .collect();
+        let exact = place_on_grid(&times, 100, 4, 4, 1.0);
+        for (k, (h, ms)) in exact.iter().zip(offsets_ms).enumerate() {
+            assert_near(h.deviation_ms, ms, 1e-9);
+            assert_eq!(
+                (h.bar, h.beat, h.tick),
+                (1, k as i64 / 4 + 1, k as i64 % 4 + 1)
+            );
+        }
+
+        let onsets = detect_onsets(&onset_envelope(&clicks(&times, 2.5)));
+        let detected = place_on_grid(&onsets, 100, 4, 4, 1.0);
+        assert_eq!(detected.len(), offsets_ms.len());
+        for (h, ms) in detected.iter().zip(offsets_ms) {
+            assert_near(h.deviation_ms, ms, 3.0);
+        }
+    }
+
+    #[test]
+    fn early_downbeat_wraps_into_the_next_bar() {
+        // 3/4 at 60 BPM: bar 2 starts at 3 s, bar 1 at 0 s.
+        let hits = place_on_grid(&[2.99, 3.02, -0.98, -0.01], 60, 3, 1, 0.0);
+        let positions: Vec<(i64, i64)> = hits.iter().map(|h| (h.bar, h.beat)).collect();
+        assert_eq!(positions, [(2, 1), (2, 1), (0, 3), (1, 1)]);
+        assert_near(hits[0].deviation_ms, -10.0, 1e-6);
+        assert_near(hits[1].deviation_ms, 20.0, 1e-6);
+        assert_near(hits[2].deviation_ms, 20.0, 1e-6);
+        assert_near(hits[3].deviation_ms, -10.0, 1e-6);
+    }
+
+    #[test]
+    fn downbeat_before_the_take() {
+        // The take starts half a beat after the downbeat at -0.25 s.
+        let hits = place_on_grid(&[0.25, 0.75], 120, 4, 1, -0.25);
+        let positions: Vec<(i64, i64)> = hits.iter().map(|h| (h.bar, h.beat)).collect();
+        assert_eq!(positions, [(1, 2), (1, 3)]);
+    }
+
+    /// A click track of `bars` bars with accented downbeats and optional
+    /// quieter eighths, starting at `start` seconds.
+    fn click_track(
+        bpm: f64,
+        beats_per_bar: usize,
+        eighths: bool,
+        start: f64,
+        bars: usize,
+    ) -> Audio {
+        let beat = 60.0 / bpm;
+        let mut notes = Vec::new();
+        for k in 0..beats_per_bar * bars {
+            let level = if k % beats_per_bar == 0 { 1.0 } else { 0.5 };
+            notes.push((start + k as f64 * beat, level));
+            if eighths {
+                notes.push((start + (k as f64 + 0.5) * beat, 0.2));
+            }
+        }
+        accented_clicks(&notes, start + (beats_per_bar * bars) as f64 * beat + 0.5)
+    }
+
+    fn estimate(audio: &Audio) -> TempoEstimate {
+        estimate_tempo(&onset_envelope(audio)).expect("no tempo estimate")
+    }
+
+    #[test]
+    fn estimates_tempo_phase_and_meter() {
+        let e = estimate(&click_track(100.0, 4, false, 0.3, 8));
+        assert_near(e.bpm, 100.0, 0.5);
+        assert_near(e.first_beat_secs, 0.3, 0.02);
+        assert_eq!(e.beats_per_bar, 4);
+        assert_near(e.first_downbeat_secs, 0.3, 0.02);
+    }
+
+    #[test]
+    fn finds_the_downbeat_of_a_bar_in_three() {
+        // Start on the last beat of a bar, so the first downbeat is beat two.
+        let beat = 60.0 / 132.0;
+        let mut notes = vec![(0.2, 0.5)];
+        for k in 0..30 {
+            let level = if k % 3 == 0 { 1.0 } else { 0.5 };
+            notes.push((0.2 + (k + 1) as f64 * beat, level));
+        }
+        let e = estimate(&accented_clicks(&notes, 0.2 + 31.0 * beat + 0.5));
+        assert_near(e.bpm, 132.0, 0.5);
+        assert_eq!(e.beats_per_bar, 3);
+        assert_near(e.first_beat_secs, 0.2, 0.02);
+        assert_near(e.first_downbeat_secs, 0.2 + beat, 0.02);
+    }
+
+    #[test]
+    fn eighths_do_not_double_the_tempo() {
+        let e = estimate(&click_track(90.0, 4, true, 0.5, 8));
+        assert_near(e.bpm, 90.0, 0.5);
+        assert_near(e.first_beat_secs, 0.5, 0.02);
+    }
+
+    #[test]
+    fn fast_and_slow_tempos_are_not_folded() {
+        let fast = estimate(&click_track(200.0, 4, false, 0.1, 12));
+        assert_near(fast.bpm, 200.0, 1.0);
+        let slow = estimate(&click_track(60.0, 4, false, 0.1, 6));
+        assert_near(slow.bpm, 60.0, 0.5);
+    }
+
+    #[test]
+    fn silence_has_no_tempo() {
+        assert!(estimate_tempo(&onset_envelope(&clicks(&[], 10.0))).is_none());
+        assert!(estimate_tempo(&onset_envelope(&clicks(&[0.1, 0.6], 1.0))).is_none());
+    }
+}
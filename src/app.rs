@@ -1,25 +1,151 @@
-use std::io::stdout;
+use std::fs::File;
+use std::io::{IsTerminal, Write, stderr, stdin, stdout};
 use std::sync::mpsc;
-use std::thread;
 use std::time::{Duration, Instant};
 
+use clap::ArgMatches;
+use clap::ValueEnum;
+use clap::parser::ValueSource;
 use crossterm::terminal;
 use crossterm::{ExecutableCommand, event};
 
-use crate::audio::spawn_audio_thread;
-use crate::cli::{Cli, Commands, SoundType, Subdivision};
-use crate::tap::tap_tempo_blocking;
-use crate::tempo::{parse_duration_ms, parse_ramp_pattern, parse_signature};
-use crate::ui::render_ui;
+use crate::analysis::{run_analyze, run_detect};
+use crate::audio::{
+    AudioMsg, AudioSetup, AudioStatus, DEFAULT_SAMPLE_RATE, Sound, SoundBank, default_device,
+    describe_device, find_device, list_devices, load_track, spawn_audio_thread,
+};
+use crate::cli::{Cli, Commands, Fallback, SoundType, Subdivision};
+use crate::config::{self, Config};
+use crate::pack::{self, SoundPack};
+use crate::practice::{PracticeSession, StatsFormat, run_stats};
+use crate::progress::{self, run_progress};
+use crate::routing::{Layer, Routing};
+use crate::sf2::SoundFontVoices;
+use crate::speech::{self, Word};
+use crate::state;
+use crate::synth::{self, SynthVoice};
+use crate::tap::{TapTempo, tap_tempo_blocking};
+use crate::tempo::{parse_duration_ms, parse_ramp_pattern, parse_signature, swung_length};
+use crate::trainer::{RetentionLog, TimingLog, nearest_offset_ms};
+use crate::ui::{UiState, render_ui};
 
-pub fn run(mut cli: Cli) {
+pub fn run(mut cli: Cli, matches: &ArgMatches) {
     if let Some(pos) = cli.bpm_positional {
         cli.bpm = pos;
     }
-    if let Some(Commands::Tap { apply: _ }) = &cli.command {
-        if let Some(bpm) = tap_tempo_blocking() {
-            cli.bpm = bpm;
+    let config = match config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("warning: ignoring config: {}", e);
+            Config::default()
+        }
+    };
+    let explicit = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    let resume = cli.resume || config.get("resume") == Some("true");
+    let saved = match resume.then(state::load) {
+        Some(Ok(saved)) => saved,
+        Some(Err(e)) => {
+            eprintln!("warning: not resuming: {}", e);
+            Config::default()
         }
+        None => Config::default(),
+    };
+    for warning in state::apply(&saved, &mut cli, explicit) {
+        eprintln!("warning: {}", warning);
+    }
+    if cli.device.is_none() {
+        cli.device = config.get("device").map(String::from);
+    }
+    let audio_offset_ms = match cli.audio_offset_ms {
+        Some(ms) => ms,
+        None => match config.get("audio_offset_ms").map(str::parse::<i64>) {
+            Some(Ok(ms)) if ms.abs() <= MAX_AUDIO_OFFSET_MS => ms,
+            Some(_) => {
+                eprintln!(
+                    "warning: ignoring audio_offset_ms in config: expected -{0} to {0}",
+                    MAX_AUDIO_OFFSET_MS
+                );
+                0
+            }
+            None => 0,
+        },
+    };
+    let routing = match Routing::from_settings(&config, &cli.pan, &cli.channels) {
+        Ok(routing) => routing,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if cli.list_devices {
+        if let Err(e) = list_devices() {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(Commands::Stats {
+        by,
+        since,
+        csv,
+        json,
+    }) = &cli.command
+    {
+        let format = if *csv {
+            StatsFormat::Csv
+        } else if *json {
+            StatsFormat::Json
+        } else {
+            StatsFormat::Table
+        };
+        if let Err(e) = run_stats(*by, since.as_deref(), format) {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let exercises = match progress::load_exercises(&config) {
+        Ok(exercises) => exercises,
+        Err(e) => {
+            eprintln!("warning: ignoring exercise goals: {}", e);
+            Vec::new()
+        }
+    };
+    if let Some(Commands::Progress { name }) = &cli.command {
+        if let Err(e) = run_progress(name.as_deref(), &exercises) {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    // The HUD keeps the exercise's target and best clean tempo in view.
+    let goal = cli.exercise.as_deref().and_then(|name| {
+        let target = exercises.iter().find(|e| e.name == name)?.target;
+        let best = progress::best(name).map_or("--".to_string(), |b| b.to_string());
+        Some(format!("{} best {}/{}", name, best, target))
+    });
+    if let Some(Commands::Tap { apply, print, json }) = &cli.command {
+        let Some(stats) = tap_tempo_blocking() else {
+            eprintln!("error: tap tempo cancelled or too few taps");
+            std::process::exit(1);
+        };
+        if *json {
+            println!("{}", stats.to_json());
+        } else if *print {
+            println!("{}", stats.rounded_bpm());
+        } else if !*apply {
+            println!(
+                "{} BPM ({:.1}, ±{:.1} ms, confidence {:.0}%)",
+                stats.rounded_bpm(),
+                stats.bpm,
+                stats.stdev_ms,
+                stats.confidence * 100.0
+            );
+        }
+        if !*apply {
+            return;
+        }
+        cli.bpm = stats.rounded_bpm();
     }
 
     let (mut numerator, mut denominator) = match parse_signature(&cli.signature) {
@@ -30,28 +156,275 @@ pub fn run(mut cli: Cli) {
         }
     };
 
+    if let Some(Commands::Detect { file, apply }) = &cli.command {
+        match run_detect(file) {
+            Ok(estimate) if *apply => {
+                cli.bpm = (estimate.bpm.round() as u16).clamp(20, 400);
+                numerator = estimate.beats_per_bar;
+                denominator = 4;
+            }
+            Ok(_) => return,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
     let mut ticks_per_beat = cli.subdivision.ticks_per_beat();
-    println!(
+    if let Some(Commands::Analyze {
+        file,
+        downbeat,
+        csv,
+    }) = &cli.command
+    {
+        let result = run_analyze(
+            file,
+            cli.bpm,
+            numerator,
+            ticks_per_beat,
+            *downbeat,
+            csv.as_deref(),
+        );
+        if let Err(e) = result {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let mut beat_in_bar: u8 = 1;
+    let mut tick_in_beat: u8 = 0;
+
+    let track = match &cli.play_file {
+        Some(path) => match load_track(path) {
+            Ok(track) => Some(track),
+            Err(e) => {
+                eprintln!("error: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let has_track = track.is_some();
+    // `--mute` silences the click; a backing track still plays.
+    let audible = !cli.mute || has_track;
+
+    // With `--output stdout` the audio owns stdout; everything else,
+    // including the UI, goes to stderr.
+    let piped = audible && cli.output.stdout();
+    let mut term = terminal_out(piped);
+    let use_device = audible && cli.output.device();
+    let device = match cli.device.as_deref().filter(|_| use_device) {
+        Some(spec) => match find_device(spec) {
+            Ok(device) => {
+                let _ = writeln!(term, "Audio device: {}", describe_device(&device));
+                Some(device)
+            }
+            Err(e) => {
+                eprintln!("error: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => {
+            if let Some(device) = default_device().filter(|_| use_device) {
+                let _ = writeln!(term, "Audio device: {} [default]", describe_device(&device));
+            }
+            None
+        }
+    };
+
+    if piped {
+        let format = cli.format.to_possible_value();
+        let _ = writeln!(
+            term,
+            "Streaming {} Hz {}-channel {} to stdout",
+            track
+                .as_ref()
+                .map(|t| t.sample_rate)
+                .unwrap_or(DEFAULT_SAMPLE_RATE),
+            routing.channels(),
+            format.as_ref().map(|v| v.get_name()).unwrap_or_default()
+        );
+    }
+    let _ = writeln!(
+        term,
         "Starting metronome: {} BPM | {}/{} | subdivision: {} per beat | mute: {}",
         cli.bpm, numerator, denominator, ticks_per_beat, cli.mute
     );
 
-    let mut beat_in_bar: u8 = 1;
-    let mut tick_in_beat: u8 = 0;
+    let mut sounds = SoundBank::default();
+    if let Some(path) = cli.sf2.as_ref().filter(|_| !cli.mute) {
+        match SoundFontVoices::load(path, &cli.sf2_note) {
+            Ok(voices) => sounds.soundfont = Some(voices),
+            Err(e) => {
+                eprintln!("error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    sounds.synths = match synth::load_voices(&config) {
+        Ok(voices) => voices,
+        Err(e) => {
+            eprintln!("warning: ignoring synth voices: {}", e);
+            Vec::new()
+        }
+    };
+    // Packs are only decoded once chosen, here or with `v`.
+    let pack_names = if cli.mute {
+        Vec::new()
+    } else {
+        pack::list_packs()
+    };
+    let mut pack_loaded = vec![None; pack_names.len()];
+    sounds.packs = pack_names.iter().map(|_| None).collect();
+    let mut chosen_pack = None;
+    if let Some(name) = cli.sound_pack.as_ref().filter(|_| !cli.mute) {
+        // The chosen pack must load.
+        match SoundPack::load(name) {
+            Ok(pack) => {
+                chosen_pack = pack_names.iter().position(|n| *n == pack.name);
+                if let Some(i) = chosen_pack {
+                    sounds.packs[i] = Some(pack);
+                    pack_loaded[i] = Some(true);
+                }
+            }
+            Err(e) => {
+                eprintln!("error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    let mut voice_names: Vec<String> = sounds.synths.iter().map(|v| v.name.clone()).collect();
+    let has_soundfont = sounds.soundfont.is_some();
+    let mut sound = match &cli.voice {
+        Some(name) => match voice_names.iter().position(|n| n == name) {
+            Some(i) => Sound::Synth(i),
+            None => {
+                eprintln!(
+                    "error: no synth voice \"{}\" in the config (defined: {})",
+                    name,
+                    if voice_names.is_empty() {
+                        "none".to_string()
+                    } else {
+                        voice_names.join(", ")
+                    }
+                );
+                std::process::exit(1);
+            }
+        },
+        None => match chosen_pack {
+            Some(i) => Sound::Pack(i),
+            None if has_soundfont => Sound::Sf2,
+            None => Sound::Builtin(cli.sound),
+        },
+    };
+    let sound_chosen = ["sound", "sound_pack", "voice", "sf2"]
+        .into_iter()
+        .any(explicit);
+    if let Some(name) = saved.get("sound").filter(|_| !sound_chosen && !cli.mute) {
+        match sound_menu(has_soundfont, &pack_names, &pack_loaded, &voice_names)
+            .into_iter()
+            .find(|&s| sound_name(s, &pack_names, &voice_names) == name)
+        {
+            Some(Sound::Pack(i)) => match load_pack(i, &pack_names, &mut pack_loaded) {
+                Ok(pack) => {
+                    sounds.packs[i] = pack;
+                    sound = Sound::Pack(i);
+                }
+                Err(e) => eprintln!("warning: skipping {}", e),
+            },
+            Some(saved_sound) => sound = saved_sound,
+            None => eprintln!("warning: saved sound \"{}\" is not available", name),
+        }
+    }
 
-    let (audio_tx, audio_rx) = mpsc::channel::<(bool, SoundType)>();
-    if !cli.mute {
-        spawn_audio_thread(audio_rx);
+    if cli.record.is_some() && !audible {
+        eprintln!("error: --record has nothing to record with --mute and no --play-file");
+        std::process::exit(1);
     }
+    let record = match &cli.record {
+        Some(path) => match File::create(path) {
+            Ok(file) => Some((file, path.clone())),
+            Err(e) => {
+                eprintln!("error: could not create {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let (audio_tx, audio_rx) = mpsc::channel::<AudioMsg>();
+    let (status_tx, status_rx) = mpsc::channel::<AudioStatus>();
+    let audio_thread = audible.then(|| {
+        let setup = AudioSetup {
+            track,
+            device,
+            output: cli.output,
+            format: cli.format,
+            routing,
+            sounds,
+            record,
+        };
+        spawn_audio_thread(audio_rx, status_tx, setup)
+    });
+    let mut audio_error: Option<String> = None;
+    let mut closed: Option<String> = None;
+    let mut notice: Option<(String, Instant)> = None;
 
     let mut next_tick = Instant::now();
     let mut playing = true;
     let mut show_help = false;
+    let mut tapper = TapTempo::new(8);
+    let mut pending_bpm: Option<u16> = None;
+    // When recent ticks were seen and heard, for placing taps on the grid.
+    let mut heard_ticks: Vec<Instant> = Vec::new();
+    let mut bars_done: u32 = 0;
+    let mut keys: Vec<(event::KeyEvent, Instant)> = Vec::new();
+
+    let mut trainer: Option<TimingLog> = None;
+    let mut bars_limit: Option<u32> = None;
+    let mut retention: Option<RetentionLog> = None;
+    let calibrating = matches!(cli.command, Some(Commands::Calibrate { .. }));
+    let audio_offset_ms = if calibrating { 0 } else { audio_offset_ms };
+    // Show the tick when the click is heard: a positive offset delays the
+    // display, a negative one delays the click. Either way the tick is
+    // perceived `heard_lag` after the grid.
+    let show_delay = Duration::from_millis(audio_offset_ms.max(0).unsigned_abs());
+    let audio_delay = Duration::from_millis(audio_offset_ms.min(0).unsigned_abs());
+    let heard_lag = Duration::from_millis(audio_offset_ms.unsigned_abs());
+    let mut schedule = Schedule::default();
+    match &cli.command {
+        Some(Commands::Train { bars, .. }) => {
+            trainer = Some(TimingLog::default());
+            bars_limit = *bars;
+        }
+        Some(Commands::Retention {
+            click_bars,
+            silent_bars,
+        }) => {
+            trainer = Some(TimingLog::default());
+            retention = Some(RetentionLog::new(*click_bars));
+            bars_limit = Some(click_bars + silent_bars);
+        }
+        Some(Commands::Calibrate { bars }) => {
+            trainer = Some(TimingLog::default());
+            bars_limit = Some(*bars);
+        }
+        _ => {}
+    }
 
     let mut ramp_from_bpm: Option<(u16, u16, u64, Instant)> = None;
     if let Some(Commands::Ramp { pattern }) = &cli.command {
         if let Some(cfg) = parse_ramp_pattern(pattern, parse_duration_ms) {
-            ramp_from_bpm = Some((cfg.from_bpm, cfg.to_bpm, cfg.duration_ms, Instant::now()));
+            // Resume an unfinished run of the same ramp where it stopped.
+            let done_ms = saved
+                .get("ramp_elapsed_ms")
+                .and_then(|ms| ms.parse::<u64>().ok())
+                .filter(|&ms| saved.get("ramp") == Some(pattern) && ms < cfg.duration_ms)
+                .unwrap_or(0);
+            let start = Instant::now() - Duration::from_millis(done_ms);
+            ramp_from_bpm = Some((cfg.from_bpm, cfg.to_bpm, cfg.duration_ms, start));
             cli.bpm = cfg.from_bpm;
         }
     }
@@ -62,7 +435,7 @@ pub fn run(mut cli: Cli) {
         let term_restored = term_restored.clone();
         let _ = ctrlc::set_handler(move || {
             if !term_restored.swap(true, std::sync::atomic::Ordering::SeqCst) {
-                cleanup_terminal();
+                cleanup_terminal(piped);
             }
             std::process::exit(0);
         });
@@ -71,73 +444,232 @@ pub fn run(mut cli: Cli) {
         let term_restored = term_restored.clone();
         Box::new(move |info| {
             if !term_restored.swap(true, std::sync::atomic::Ordering::SeqCst) {
-                cleanup_terminal();
+                cleanup_terminal(piped);
             }
             eprintln!("panic: {}", info);
         })
     });
-    let mut stdout_handle = stdout();
-    let _ = stdout_handle.execute(crossterm::terminal::EnterAlternateScreen);
-    let _ = stdout_handle.execute(crossterm::terminal::DisableLineWrap);
-    let _ = stdout_handle.execute(crossterm::cursor::Hide);
+    let _ = term.execute(crossterm::terminal::EnterAlternateScreen);
+    let _ = term.execute(crossterm::terminal::DisableLineWrap);
+    let _ = term.execute(crossterm::cursor::Hide);
 
-    loop {
+    // Counts playing time, pauses excluded, for the timer and the log.
+    let mut practice = PracticeSession::new(cli.exercise.as_deref());
+    let bars_limit = bars_limit.or(cli.bars);
+    let duration = cli.duration.map(Duration::from_millis);
+    let blocks = cli
+        .blocks
+        .map(|(play, rest)| (Duration::from_millis(play), Duration::from_millis(rest)));
+    // Playing time when the current block started, and the end of a break.
+    let mut block_start = Duration::ZERO;
+    let mut blocks_done: u32 = 0;
+    let mut break_until: Option<Instant> = None;
+    let mut speed = cli.speed;
+    // A pause or resume of the track, sent along with the next click.
+    let mut track_switch: Option<bool> = None;
+    // Count-in beats still to play; the grid waits for them, and a track
+    // starts `track_lead` before its first grid tick.
+    let mut count_in = cli.count_in.unwrap_or(0) * numerator as u32;
+    let mut track_lead = Duration::ZERO;
+    if count_in > 0 {
+        playing = false;
+    }
+    if has_track {
+        // Extend the grid back from the first downbeat so the intro is
+        // clicked too, and start on the first grid tick inside the file.
+        // Grid times are in track time; wall time runs 1/speed slower.
+        let tick_secs = 60.0 / cli.bpm as f64 / ticks_per_beat as f64;
+        let offset_secs = cli.offset as f64 / 1000.0;
+        let before = (offset_secs / tick_secs).floor();
+        let ticks_per_bar = numerator as i64 * ticks_per_beat as i64;
+        let index = (-(before as i64)).rem_euclid(ticks_per_bar);
+        beat_in_bar = (index / ticks_per_beat as i64) as u8 + 1;
+        tick_in_beat = (index % ticks_per_beat as i64) as u8;
+        let _ = audio_tx.send(AudioMsg::TrackSpeed(speed));
+        track_lead = Duration::from_secs_f64((offset_secs - before * tick_secs) / speed);
+        if count_in == 0 {
+            let start = Instant::now();
+            let at = start + audio_delay;
+            schedule.at(at, Due::Send(AudioMsg::Track { playing: true, at }));
+            next_tick = start + track_lead;
+            wait_until(next_tick, &mut keys, &mut schedule, &mut term, &audio_tx);
+        }
+    }
+
+    'run: loop {
         while event::poll(Duration::from_millis(0)).unwrap_or(false) {
-            if let Ok(ev) = event::read() {
-                if let event::Event::Key(key) = ev {
-                    use crossterm::event::KeyCode;
-                    match key.code {
-                        KeyCode::Char('q') | KeyCode::Esc => {
-                            cleanup_terminal();
-                            return;
-                        }
-                        KeyCode::Char(' ') => {
-                            playing = !playing;
-                        }
-                        KeyCode::Up => {
-                            if cli.bpm < 400 {
-                                cli.bpm = (cli.bpm + 1).min(400);
-                            }
-                        }
-                        KeyCode::Down => {
-                            if cli.bpm > 20 {
-                                cli.bpm = (cli.bpm.saturating_sub(1)).max(20);
-                            }
-                        }
-                        KeyCode::Right => {
-                            cli.bpm = (cli.bpm + 5).min(400);
-                        }
-                        KeyCode::Left => {
-                            cli.bpm = cli.bpm.saturating_sub(5).max(20);
-                        }
-                        KeyCode::Char('s') => {
-                            cli.subdivision = match cli.subdivision {
-                                Subdivision::Quarter => Subdivision::Eighth,
-                                Subdivision::Eighth => Subdivision::Triplet,
-                                Subdivision::Triplet => Subdivision::Sixteenth,
-                                Subdivision::Sixteenth => Subdivision::Quarter,
-                            };
-                            ticks_per_beat = cli.subdivision.ticks_per_beat();
+            if let Ok(event::Event::Key(key)) = event::read() {
+                keys.push((key, Instant::now()));
+            }
+        }
+        for (key, at) in keys.drain(..) {
+            use crossterm::event::KeyCode;
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => {
+                    break 'run;
+                }
+                // Taps during a count-in are a warm-up; otherwise Space stops it.
+                KeyCode::Char(' ') if count_in > 0 && trainer.is_some() => {}
+                KeyCode::Char(' ') | KeyCode::Char('p') if count_in > 0 => {
+                    count_in = 0;
+                }
+                KeyCode::Char(' ') | KeyCode::Char('p') if break_until.is_some() => {
+                    // Cut the break short.
+                    break_until = Some(Instant::now());
+                }
+                // Space is the tap key while training; p still pauses.
+                KeyCode::Char(' ') if playing && trainer.is_some() => {
+                    if let Some(log) = trainer.as_mut() {
+                        match retention.as_mut() {
+                            Some(r) if r.is_silent() => r.record(at),
+                            _ => log.record(nearest_offset_ms(
+                                at,
+                                heard_ticks.iter().copied().chain([next_tick + heard_lag]),
+                            )),
                         }
-                        KeyCode::Tab => {
-                            let next = match (numerator, denominator) {
-                                (4, 4) => (3, 4),
-                                (3, 4) => (6, 8),
-                                (6, 8) => (7, 8),
-                                _ => (4, 4),
-                            };
-                            numerator = next.0;
-                            denominator = next.1;
-                            if beat_in_bar > numerator {
-                                beat_in_bar = 1;
+                    }
+                }
+                KeyCode::Char(' ') | KeyCode::Char('p') if !playing && cli.count_in_resume => {
+                    count_in = cli.count_in.unwrap_or(0) * numerator as u32;
+                    // A track picks up where it paused; the click alone
+                    // comes back in on a downbeat.
+                    if !has_track {
+                        beat_in_bar = 1;
+                        tick_in_beat = 0;
+                    }
+                }
+                KeyCode::Char(' ') | KeyCode::Char('p') => {
+                    playing = !playing;
+                    if has_track {
+                        track_switch = Some(playing);
+                    }
+                }
+                KeyCode::Up if cli.bpm < 400 => {
+                    cli.bpm = (cli.bpm + 1).min(400);
+                }
+                KeyCode::Down if cli.bpm > 20 => {
+                    cli.bpm = (cli.bpm.saturating_sub(1)).max(20);
+                }
+                KeyCode::Right => {
+                    cli.bpm = (cli.bpm + 5).min(400);
+                }
+                KeyCode::Left => {
+                    cli.bpm = cli.bpm.saturating_sub(5).max(20);
+                }
+                KeyCode::Char('s') => {
+                    cli.subdivision = match cli.subdivision {
+                        Subdivision::Quarter => Subdivision::Eighth,
+                        Subdivision::Eighth => Subdivision::Triplet,
+                        Subdivision::Triplet => Subdivision::Sixteenth,
+                        Subdivision::Sixteenth => Subdivision::Quarter,
+                    };
+                    ticks_per_beat = cli.subdivision.ticks_per_beat();
+                }
+                KeyCode::Tab => {
+                    let next = match (numerator, denominator) {
+                        (4, 4) => (3, 4),
+                        (3, 4) => (6, 8),
+                        (6, 8) => (7, 8),
+                        _ => (4, 4),
+                    };
+                    numerator = next.0;
+                    denominator = next.1;
+                    if beat_in_bar > numerator {
+                        beat_in_bar = 1;
+                    }
+                }
+                KeyCode::Char('[') | KeyCode::Char(']') => {
+                    let step = if key.code == KeyCode::Char(']') {
+                        0.05
+                    } else {
+                        -0.05
+                    };
+                    speed = ((speed + step) * 20.0).round() / 20.0;
+                    speed = speed.clamp(0.25, 1.5);
+                    if has_track {
+                        let _ = audio_tx.send(AudioMsg::TrackSpeed(speed));
+                    }
+                }
+                KeyCode::Char(',') | KeyCode::Char('.') => {
+                    let step = if key.code == KeyCode::Char('.') {
+                        0.02
+                    } else {
+                        -0.02
+                    };
+                    cli.swing = ((cli.swing + step) * 50.0).round() / 50.0;
+                    cli.swing = cli.swing.clamp(0.5, 0.75);
+                }
+                KeyCode::Char('h') => {
+                    show_help = !show_help;
+                }
+                KeyCode::Char('v') => {
+                    let menu = sound_menu(has_soundfont, &pack_names, &pack_loaded, &voice_names);
+                    let i = menu.iter().position(|s| *s == sound).unwrap_or(0);
+                    let mut skipped = Vec::new();
+                    for step in 1..=menu.len() {
+                        let next = menu[(i + step) % menu.len()];
+                        if let Sound::Pack(p) = next {
+                            match load_pack(p, &pack_names, &mut pack_loaded) {
+                                Ok(Some(pack)) => {
+                                    let _ = audio_tx.send(AudioMsg::Pack(p, pack));
+                                }
+                                Ok(None) => {}
+                                Err(e) => {
+                                    skipped.push(format!("skipping {}", e));
+                                    continue;
+                                }
                             }
                         }
-                        KeyCode::Char('h') => {
-                            show_help = !show_help;
-                        }
-                        _ => {}
+                        sound = next;
+                        break;
                     }
+                    if !skipped.is_empty() {
+                        notice = Some((skipped.join("; "), Instant::now()));
+                    }
+                    if !cli.mute {
+                        let _ = audio_tx.send(AudioMsg::Click {
+                            sound,
+                            layer: Layer::Accent,
+                        });
+                    }
+                }
+                KeyCode::Char('r') => {
+                    let reloaded = config::load().and_then(|c| synth::load_voices(&c));
+                    notice = Some((
+                        match reloaded {
+                            Ok(voices) => {
+                                let text = format!("reloaded {} voices", voices.len());
+                                sound = reload_sound(sound, &voice_names, &voices, cli.sound);
+                                voice_names = voices.iter().map(|v| v.name.clone()).collect();
+                                let _ = audio_tx.send(AudioMsg::Synths(voices));
+                                text
+                            }
+                            Err(e) => format!("voices not reloaded: {}", e),
+                        },
+                        Instant::now(),
+                    ));
                 }
+                KeyCode::Char('t') => {
+                    tapper.tap(at);
+                }
+                KeyCode::Enter if tapper.len() >= 4 => {
+                    pending_bpm = tapper.bpm();
+                    tapper.reset();
+                }
+                KeyCode::Backspace => {
+                    tapper.reset();
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(bpm) = pending_bpm {
+            let on_downbeat = tick_in_beat == 0 && beat_in_bar == 1;
+            if !cli.tap_quantize || !playing || on_downbeat {
+                // Taps are heard at the current speed; store the full-speed tempo.
+                cli.bpm = ((bpm as f64 / speed).round() as u16).clamp(20, 400);
+                pending_bpm = None;
+                ramp_from_bpm = None;
             }
         }
 
@@ -148,66 +680,293 @@ pub fn run(mut cli: Cli) {
             cli.bpm = bpm_now.round().clamp(20.0, 400.0) as u16;
         }
 
-        let beats_per_second = cli.bpm as f64 / 60.0;
+        let beats_per_second = cli.bpm as f64 * speed / 60.0;
         let ticks_per_second = beats_per_second * ticks_per_beat as f64;
         let nanos_per_tick = (1_000_000_000f64 / ticks_per_second) as u64;
         let base_tick_duration = Duration::from_nanos(nanos_per_tick);
+        let signature = format!("{}/{}", numerator, denominator);
+        practice.update(playing, (cli.bpm as f64 * speed).round() as u16, &signature);
+        let played = practice.played();
+        // Playing time is measured just before the tick; round it to the
+        // nearest tick.
+        let half_tick = base_tick_duration / 2;
+        if duration.is_some_and(|d| played + half_tick >= d) {
+            break;
+        }
+        if let Some((play, rest)) = blocks {
+            let now = Instant::now();
+            let change = match break_until {
+                Some(end) if now >= end => {
+                    break_until = None;
+                    block_start = played;
+                    true
+                }
+                None if playing && played - block_start + half_tick >= play => {
+                    break_until = Some(now + rest);
+                    blocks_done += 1;
+                    true
+                }
+                _ => false,
+            };
+            if change {
+                playing = break_until.is_none();
+                if playing {
+                    // A track continues from where it paused, so the click
+                    // keeps its place in the bar.
+                    if !has_track {
+                        beat_in_bar = 1;
+                        tick_in_beat = 0;
+                    }
+                    if cli.count_in_resume {
+                        count_in = cli.count_in.unwrap_or(0) * numerator as u32;
+                        playing = false;
+                    }
+                }
+                if has_track {
+                    track_switch = Some(playing);
+                }
+                if !cli.mute {
+                    let _ = audio_tx.send(AudioMsg::Click {
+                        sound: Sound::Chime,
+                        layer: Layer::Accent,
+                    });
+                }
+            }
+        }
 
-        let (term_w, term_h) = terminal::size().unwrap_or((80, 24));
-        if playing {
-            let is_accent = tick_in_beat == 0 && beat_in_bar == 1;
-            render_ui(
-                &mut stdout_handle,
-                term_w,
-                term_h,
-                cli.bpm,
-                numerator,
-                denominator,
-                ticks_per_beat,
-                beat_in_bar,
-                tick_in_beat,
-                true,
-                is_accent,
-                show_help,
+        let counting = count_in > 0;
+        let is_accent = playing && tick_in_beat == 0 && beat_in_bar == 1;
+        if let Some(r) = retention.as_mut() {
+            if playing && is_accent && bars_done >= r.click_bars {
+                r.begin(next_tick + heard_lag, beats_per_second * 60.0, numerator);
+            }
+        }
+        let silent = retention.as_ref().is_some_and(|r| r.is_silent());
+        // Calibration hides the display so taps follow the sound alone.
+        let blind = silent || calibrating;
+        for status in status_rx.try_iter() {
+            match status {
+                AudioStatus::Playing => audio_error = None,
+                AudioStatus::Failed(e) => audio_error = Some(e),
+                AudioStatus::Closed(e) => closed = Some(e),
+                AudioStatus::Buffer {
+                    frames,
+                    sample_rate,
+                } => {
+                    let ms = frames as f64 * 1000.0 / sample_rate.max(1) as f64;
+                    notice = Some((
+                        format!("audio buffer {} frames ({:.1} ms)", frames, ms),
+                        Instant::now(),
+                    ));
+                }
+            }
+        }
+        let mut hud_extra = Vec::new();
+        if let Some(e) = &closed {
+            hud_extra.push(format!("AUDIO: {}", e));
+        }
+        if let Some(e) = &audio_error {
+            hud_extra.push(format!("AUDIO: {}, retrying", e));
+        }
+        if !cli.mute {
+            hud_extra.push(sound_name(sound, &pack_names, &voice_names));
+        }
+        notice = notice.filter(|(_, at)| at.elapsed() < NOTICE_DURATION);
+        if let Some((text, _)) = &notice {
+            hud_extra.push(text.clone());
+        }
+        if !tapper.is_empty() {
+            let est = tapper
+                .stats()
+                .map(|s| format!("{:.1} {:.0}%", s.bpm, s.confidence * 100.0))
+                .unwrap_or("--".to_string());
+            hud_extra.push(format!("tap {} ({})", est, tapper.len()));
+        }
+        if (speed - 1.0).abs() > f64::EPSILON {
+            hud_extra.push(format!("speed {:.0}%", speed * 100.0));
+        }
+        if cli.swing > 0.5 {
+            hud_extra.push(format!("swing {:.0}%", cli.swing * 100.0));
+        }
+        if let Some(bpm) = pending_bpm {
+            hud_extra.push(format!("next bar {}", bpm));
+        }
+        if let Some(goal) = &goal {
+            hud_extra.push(goal.clone());
+        }
+        if calibrating {
+            hud_extra.push(format!(
+                "CALIBRATING: tap along with the click  bar {}/{}  taps {}",
+                (bars_done + 1).min(bars_limit.unwrap_or(0)),
+                bars_limit.unwrap_or(0),
+                trainer.as_ref().map(|l| l.len()).unwrap_or(0)
+            ));
+        } else if let Some(r) = &retention {
+            if silent {
+                hud_extra.push(format!(
+                    "SILENT bar {}/{}  taps {}",
+                    bars_done + 1 - r.click_bars,
+                    bars_limit.unwrap_or(0) - r.click_bars,
+                    r.len()
+                ));
+            } else {
+                hud_extra.push(format!("click bar {}/{}", bars_done + 1, r.click_bars));
+            }
+        } else if let Some(log) = &trainer {
+            let bars = match bars_limit {
+                Some(limit) => format!("bar {}/{}", (bars_done + 1).min(limit), limit),
+                None => format!("bar {}", bars_done + 1),
+            };
+            hud_extra.push(format!("{}  taps {}", bars, log.len()));
+        } else if let Some(limit) = bars_limit {
+            hud_extra.push(format!("bar {}/{}", (bars_done + 1).min(limit), limit));
+        }
+        let mut timer = format!("time {}", clock(played));
+        if let Some(d) = duration {
+            timer.push_str(&format!("  {} left", clock(d.saturating_sub(played))));
+        }
+        hud_extra.push(timer);
+        match (blocks, break_until) {
+            (Some(_), Some(end)) => hud_extra.push(format!(
+                "BREAK {}  <Space> resumes",
+                clock(end.saturating_duration_since(Instant::now()))
+            )),
+            (Some((play, _)), None) => hud_extra.push(format!(
+                "block {} {} left",
+                blocks_done + 1,
+                clock(play.saturating_sub(played - block_start))
+            )),
+            _ => {}
+        }
+        let layer = if is_accent {
+            Layer::Accent
+        } else if tick_in_beat == 0 {
+            Layer::Beat
+        } else {
+            Layer::Sub
+        };
+        let click = if counting {
+            // Count bars start at a multiple of the signature.
+            let layer = if count_in % numerator as u32 == 0 {
+                Layer::Accent
+            } else {
+                Layer::Beat
+            };
+            let sound = match sound {
+                Sound::Pack(i) => Sound::CountIn(Some(i)),
+                // Counting aloud counts the count-in too.
+                Sound::Builtin(SoundType::Count) => Sound::Spoken(Word::Number(
+                    numerator - ((count_in - 1) % numerator as u32) as u8,
+                )),
+                _ => Sound::CountIn(None),
+            };
+            (!cli.mute).then_some(AudioMsg::Click { sound, layer })
+        } else {
+            let sound = match sound {
+                Sound::Builtin(SoundType::Count) => Sound::Spoken(speech::count_word(
+                    beat_in_bar,
+                    tick_in_beat,
+                    ticks_per_beat,
+                )),
+                sound => sound,
+            };
+            (playing && !cli.mute && !silent).then_some(AudioMsg::Click { sound, layer })
+        };
+        // Never leave the user without a click: stand in for a dead output.
+        let fallback = click.is_some() && audio_error.is_some();
+        let ui_state = UiState {
+            bpm: (cli.bpm as f64 * speed).round() as u16,
+            bar_beats: numerator,
+            denom: denominator,
+            ticks_per_beat,
+            beat_in_bar,
+            tick_in_beat,
+            playing,
+            accent: is_accent,
+            show_help,
+            hud_extra,
+            training: trainer.is_some(),
+            tap_offset_ms: trainer
+                .as_ref()
+                .and_then(|log| log.last())
+                .filter(|_| !blind),
+            blind,
+            flash: fallback && cli.fallback == Fallback::Flash,
+            count_in: counting.then_some(count_in),
+            swing: cli.swing,
+        };
+        // The track pauses and resumes on the tick, keeping its place on
+        // the click grid however late this runs.
+        let click_at = next_tick + audio_delay;
+        if let Some(playing) = track_switch.take() {
+            schedule.at(
+                click_at,
+                Due::Send(AudioMsg::Track {
+                    playing,
+                    at: click_at,
+                }),
             );
-            if !cli.mute {
-                let _ = audio_tx.send((is_accent, cli.sound));
+        }
+        if let Some(msg) = click {
+            schedule.at(click_at, Due::Send(msg));
+        }
+        let show_at = if playing || counting {
+            next_tick + show_delay
+        } else {
+            Instant::now()
+        };
+        match cli.fallback {
+            Fallback::Bell if fallback => schedule.at(click_at, Due::Bell),
+            Fallback::Flash if fallback => {
+                let unlit = UiState {
+                    flash: false,
+                    ..ui_state.clone()
+                };
+                schedule.at(show_at, Due::Show(ui_state));
+                schedule.at(
+                    show_at + FLASH_DURATION.min(base_tick_duration / 2),
+                    Due::Show(unlit),
+                );
             }
-            let tick_duration = base_tick_duration;
+            _ => schedule.at(show_at, Due::Show(ui_state)),
+        }
+        run_due(&mut schedule, &mut term, &audio_tx);
+        // A count-in clicks once a beat; swing stretches and shortens the
+        // ticks of a pair.
+        let tick_duration = if counting {
+            base_tick_duration * ticks_per_beat as u32
+        } else {
+            base_tick_duration.mul_f64(swung_length(tick_in_beat, ticks_per_beat, cli.swing))
+        };
+        if playing || counting {
+            if heard_ticks.len() == HEARD_TICKS {
+                heard_ticks.remove(0);
+            }
+            heard_ticks.push(next_tick + heard_lag);
             next_tick += tick_duration;
         } else {
-            render_ui(
-                &mut stdout_handle,
-                term_w,
-                term_h,
-                cli.bpm,
-                numerator,
-                denominator,
-                ticks_per_beat,
-                beat_in_bar,
-                tick_in_beat,
-                false,
-                false,
-                show_help,
-            );
             next_tick = Instant::now() + base_tick_duration;
         }
+        if counting {
+            count_in -= 1;
+            if count_in == 0 && has_track {
+                if track_lead.is_zero() {
+                    // Resuming: the track starts again with the next click.
+                    track_switch = Some(true);
+                } else {
+                    // Start the track so its first grid tick follows the count.
+                    let at = next_tick - track_lead + audio_delay;
+                    schedule.at(at, Due::Send(AudioMsg::Track { playing: true, at }));
+                    track_lead = Duration::ZERO;
+                }
+            }
+        }
 
         let now = Instant::now();
         if next_tick > now {
-            let mut remaining = next_tick - now;
-            while remaining > Duration::from_millis(2) {
-                thread::sleep(remaining - Duration::from_millis(2));
-                let now_inner = Instant::now();
-                if next_tick <= now_inner {
-                    break;
-                }
-                remaining = next_tick - now_inner;
-            }
-            while Instant::now() < next_tick {}
+            wait_until(next_tick, &mut keys, &mut schedule, &mut term, &audio_tx);
         } else if playing {
             let behind = now - next_tick;
-            let tick_duration = base_tick_duration;
             let ticks_behind = (behind.as_nanos() / tick_duration.as_nanos().max(1)) as u64;
             next_tick = now + tick_duration;
             if ticks_behind > 0 {
@@ -218,11 +977,12 @@ pub fn run(mut cli: Cli) {
                     beat_in_bar += 1;
                     if beat_in_bar > numerator {
                         beat_in_bar = 1;
+                        bars_done += 1;
                     }
                 }
             }
         } else {
-            next_tick = now + base_tick_duration;
+            next_tick = now + tick_duration;
         }
 
         if playing {
@@ -232,16 +992,364 @@ pub fn run(mut cli: Cli) {
                 beat_in_bar += 1;
                 if beat_in_bar > numerator {
                     beat_in_bar = 1;
+                    bars_done += 1;
+                }
+            }
+        }
+        if counting && count_in == 0 {
+            playing = true;
+        }
+
+        if bars_limit.is_some_and(|limit| bars_done >= limit) {
+            break;
+        }
+    }
+
+    cleanup_terminal(piped);
+    let mut session = vec![
+        ("bpm", cli.bpm.to_string()),
+        ("signature", format!("{}/{}", numerator, denominator)),
+        (
+            "subdivision",
+            cli.subdivision
+                .to_possible_value()
+                .map(|v| v.get_name().to_string())
+                .unwrap_or_default(),
+        ),
+        ("speed", format!("{:.0}%", speed * 100.0)),
+        ("swing", format!("{:.0}%", cli.swing * 100.0)),
+    ];
+    if !cli.mute {
+        session.push(("sound", sound_name(sound, &pack_names, &voice_names)));
+    }
+    if let Some(Commands::Ramp { pattern }) = &cli.command {
+        // A ramp overridden by a tapped tempo counts as finished.
+        let elapsed_ms = match ramp_from_bpm {
+            Some((_, _, _, start)) => start.elapsed().as_millis() as u64,
+            None => u64::MAX,
+        };
+        session.push(("ramp", pattern.clone()));
+        session.push(("ramp_elapsed_ms", elapsed_ms.to_string()));
+    }
+    if let Err(e) = state::save(&session) {
+        eprintln!("warning: could not save the session: {}", e);
+    }
+    let signature = format!("{}/{}", numerator, denominator);
+    practice.update(playing, (cli.bpm as f64 * speed).round() as u16, &signature);
+    // Calibration is setup rather than practice, so it is not logged.
+    if !calibrating && practice.played() >= Duration::from_secs(1) {
+        if let Err(e) = practice.save() {
+            eprintln!("warning: could not log the session: {}", e);
+        }
+    }
+    // Closing the channel stops the audio thread, which finishes the
+    // recording.
+    drop(audio_tx);
+    if let Some(handle) = audio_thread {
+        match handle.join() {
+            Ok(Err(e)) => eprintln!("error: {}", e),
+            Err(_) => eprintln!("error: audio thread panicked"),
+            Ok(Ok(())) => {
+                if let Some(path) = &cli.record {
+                    let _ = writeln!(term, "Recorded session to {}", path.display());
                 }
             }
         }
     }
+    if calibrating {
+        match trainer
+            .as_ref()
+            .and_then(|log| log.median().map(|m| (m, log.len())))
+        {
+            Some((median, taps)) if taps >= CALIBRATION_MIN_TAPS => {
+                let ms = median.round() as i64;
+                let _ = writeln!(
+                    term,
+                    "Measured audio offset: {:+} ms over {} taps",
+                    ms, taps
+                );
+                if ms.abs() > MAX_AUDIO_OFFSET_MS {
+                    let _ = writeln!(
+                        term,
+                        "That is beyond ±{} ms; nothing was saved.",
+                        MAX_AUDIO_OFFSET_MS
+                    );
+                } else {
+                    match config::set_value("audio_offset_ms", &ms.to_string()) {
+                        Ok(path) => {
+                            let _ = writeln!(
+                                term,
+                                "Saved audio_offset_ms = {} to {}",
+                                ms,
+                                path.display()
+                            );
+                        }
+                        Err(e) => eprintln!("error: {}", e),
+                    }
+                }
+            }
+            _ => {
+                let _ = writeln!(
+                    term,
+                    "Calibration needs at least {} taps; nothing was saved.",
+                    CALIBRATION_MIN_TAPS
+                );
+            }
+        }
+    } else if let Some(r) = &retention {
+        let _ = writeln!(term, "With click:");
+        let _ = write!(
+            term,
+            "{}",
+            trainer
+                .as_ref()
+                .map(|l| l.report("Taps"))
+                .unwrap_or_default()
+        );
+        let _ = writeln!(term, "Without click:");
+        let _ = write!(term, "{}", r.report());
+    } else if let Some(log) = &trainer {
+        let _ = write!(term, "{}", log.report("Taps"));
+        if let Some(Commands::Train {
+            save: Some(path), ..
+        }) = &cli.command
+        {
+            match log.write_csv(path) {
+                Ok(()) => {
+                    let _ = writeln!(term, "Saved {} taps to {}", log.len(), path.display());
+                }
+                Err(e) => eprintln!("error: could not write {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    // Note the exercise's clean tempo: a steady trainer run counts on its
+    // own, a plain session asks.
+    let played = practice.played();
+    if let Some(name) = cli.exercise.as_deref().filter(|_| !calibrating) {
+        let bpm = (cli.bpm as f64 * speed).round() as u16;
+        let clean = match (&cli.command, &trainer) {
+            (Some(Commands::Train { .. }), Some(log)) if log.is_clean() => Some((bpm, "train")),
+            (_, None) if played >= Duration::from_secs(1) && stdin().is_terminal() => {
+                ask_clean_tempo(&mut term, name, bpm).map(|bpm| (bpm, "entered"))
+            }
+            _ => None,
+        };
+        if let Some((bpm, source)) = clean {
+            match progress::record(name, bpm, source) {
+                Ok(_) => {
+                    let _ = writeln!(term, "Recorded {} BPM for {}", bpm, name);
+                }
+                Err(e) => eprintln!("error: {}", e),
+            }
+        }
+    }
+}
+
+/// Asks for the highest tempo `exercise` was played cleanly at, offering
+/// `bpm`; a blank answer takes it and 0 or anything else skips.
+fn ask_clean_tempo(term: &mut impl Write, exercise: &str, bpm: u16) -> Option<u16> {
+    let _ = write!(
+        term,
+        "Highest clean tempo for {} today? [Enter = {}, 0 = skip] ",
+        exercise, bpm
+    );
+    let _ = term.flush();
+    let mut answer = String::new();
+    stdin().read_line(&mut answer).ok()?;
+    match answer.trim() {
+        "" => Some(bpm),
+        answer => answer
+            .parse::<u16>()
+            .ok()
+            .filter(|b| (20..=400).contains(b)),
+    }
+}
+
+/// `m:ss`, or `h:mm:ss` from an hour on.
+fn clock(d: Duration) -> String {
+    let secs = d.as_secs();
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+}
+
+/// How long a message such as a voice reload result stays on the HUD.
+const NOTICE_DURATION: Duration = Duration::from_secs(3);
+
+/// Sounds `v` cycles through: the built-ins, then sound packs, config
+/// voices and the soundfont.
+fn sound_menu(
+    has_soundfont: bool,
+    pack_names: &[String],
+    pack_loaded: &[Option<bool>],
+    voice_names: &[String],
+) -> Vec<Sound> {
+    SoundType::value_variants()
+        .iter()
+        .map(|&s| Sound::Builtin(s))
+        .chain(
+            (0..pack_names.len())
+                .filter(|&i| pack_loaded.get(i) != Some(&Some(false)))
+                .map(Sound::Pack),
+        )
+        .chain((0..voice_names.len()).map(Sound::Synth))
+        .chain(has_soundfont.then_some(Sound::Sf2))
+        .collect()
+}
+
+fn sound_name(sound: Sound, pack_names: &[String], voice_names: &[String]) -> String {
+    match sound {
+        Sound::Builtin(s) => s
+            .to_possible_value()
+            .map(|v| v.get_name().to_string())
+            .unwrap_or_default(),
+        Sound::Sf2 => "sf2".to_string(),
+        Sound::Chime => "chime".to_string(),
+        Sound::CountIn(_) => "count-in".to_string(),
+        Sound::Spoken(_) => "count".to_string(),
+        Sound::Synth(i) => format!("voice {}", voice_names.get(i).map_or("?", |n| n)),
+        Sound::Pack(i) => format!("pack {}", pack_names.get(i).map_or("?", |n| n)),
+    }
+}
+
+/// Loads pack `i` the first time it is chosen, returning it to hand to the
+/// audio thread, or `None` if that was done before. A pack that fails is
+/// left out of `sound_menu` from then on.
+fn load_pack(
+    i: usize,
+    pack_names: &[String],
+    pack_loaded: &mut [Option<bool>],
+) -> Result<Option<SoundPack>, String> {
+    if pack_loaded[i] == Some(true) {
+        return Ok(None);
+    }
+    let loaded = SoundPack::load(&pack_names[i]);
+    pack_loaded[i] = Some(loaded.is_ok());
+    loaded.map(Some)
+}
+
+/// The sound to keep after voices are reloaded: the same voice by name,
+/// else the `--sound` built-in.
+fn reload_sound(sound: Sound, old: &[String], new: &[SynthVoice], builtin: SoundType) -> Sound {
+    match sound {
+        Sound::Synth(i) => old
+            .get(i)
+            .and_then(|name| new.iter().position(|v| &v.name == name))
+            .map_or(Sound::Builtin(builtin), Sound::Synth),
+        other => other,
+    }
+}
+
+/// How long the beat bar stays lit for a `--fallback flash` click.
+const FLASH_DURATION: Duration = Duration::from_millis(80);
+
+/// Fewer calibration taps than this are not trusted.
+const CALIBRATION_MIN_TAPS: usize = 8;
+
+/// The largest `--audio-offset-ms` either way; the click and the display
+/// are never held back longer than this.
+const MAX_AUDIO_OFFSET_MS: i64 = 1000;
+
+/// Heard tick times kept for taps, enough to span `MAX_AUDIO_OFFSET_MS`
+/// at the fastest subdivided tempo.
+const HEARD_TICKS: usize = 32;
+
+/// Output that is due at a set time rather than on the tick.
+enum Due {
+    Send(AudioMsg),
+    Show(UiState),
+    Bell,
+}
+
+/// Output waiting for its time, so the click and the display can each lag
+/// the tick by their own offset.
+#[derive(Default)]
+struct Schedule(Vec<(Instant, Due)>);
+
+impl Schedule {
+    fn at(&mut self, at: Instant, due: Due) {
+        self.0.push((at, due));
+    }
+
+    fn next(&self) -> Option<Instant> {
+        self.0.iter().map(|(at, _)| *at).min()
+    }
+
+    /// Removes what is due by `now`, earliest first.
+    fn take_due(&mut self, now: Instant) -> Vec<Due> {
+        self.0.sort_by_key(|(at, _)| *at);
+        let due = self.0.partition_point(|(at, _)| *at <= now);
+        self.0.drain(..due).map(|(_, due)| due).collect()
+    }
+}
+
+fn run_due(schedule: &mut Schedule, term: &mut impl Write, audio_tx: &mpsc::Sender<AudioMsg>) {
+    for due in schedule.take_due(Instant::now()) {
+        match due {
+            Due::Send(msg) => {
+                let _ = audio_tx.send(msg);
+            }
+            Due::Show(state) => {
+                let (width, height) = terminal::size().unwrap_or((80, 24));
+                render_ui(term, width, height, &state);
+            }
+            Due::Bell => {
+                let _ = write!(term, "\x07");
+                let _ = term.flush();
+            }
+        }
+    }
+}
+
+/// Waits until `deadline`, collecting key presses as they arrive and
+/// running scheduled output on time.
+fn wait_until(
+    deadline: Instant,
+    keys: &mut Vec<(event::KeyEvent, Instant)>,
+    schedule: &mut Schedule,
+    term: &mut impl Write,
+    audio_tx: &mpsc::Sender<AudioMsg>,
+) {
+    let margin = Duration::from_millis(2);
+    loop {
+        run_due(schedule, term, audio_tx);
+        let until = schedule.next().map_or(deadline, |at| at.min(deadline));
+        let Some(remaining) = until.checked_duration_since(Instant::now()) else {
+            if Instant::now() >= deadline {
+                break;
+            }
+            continue;
+        };
+        if remaining <= margin {
+            while Instant::now() < until {}
+            continue;
+        }
+        // Wait on the terminal rather than sleeping so key presses are
+        // timestamped when they arrive, not at the next tick.
+        if event::poll(remaining - margin).unwrap_or(false) {
+            if let Ok(event::Event::Key(key)) = event::read() {
+                keys.push((key, Instant::now()));
+            }
+        }
+    }
+}
+
+/// Where the UI and messages go: stderr when stdout carries audio.
+fn terminal_out(piped: bool) -> Box<dyn Write> {
+    if piped {
+        Box::new(stderr())
+    } else {
+        Box::new(stdout())
+    }
 }
 
-pub fn cleanup_terminal() {
-    let mut stdout_handle = stdout();
-    let _ = stdout_handle.execute(crossterm::cursor::Show);
-    let _ = stdout_handle.execute(crossterm::terminal::EnableLineWrap);
-    let _ = stdout_handle.execute(crossterm::terminal::LeaveAlternateScreen);
+pub fn cleanup_terminal(piped: bool) {
+    let mut term = terminal_out(piped);
+    let _ = term.execute(crossterm::cursor::Show);
+    let _ = term.execute(crossterm::terminal::EnableLineWrap);
+    let _ = term.execute(crossterm::terminal::LeaveAlternateScreen);
     let _ = terminal::disable_raw_mode();
 }
@@ -1,75 +1,593 @@
-use std::thread;
-use std::time::Duration;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-use crate::cli::SoundType;
+use rodio::cpal::traits::HostTrait;
+use rodio::cpal::{self, SupportedBufferSize};
+use rodio::{Decoder, Device, DeviceTrait, OutputStream, Source};
 
-pub fn spawn_audio_thread(rx: std::sync::mpsc::Receiver<(bool, SoundType)>) {
-    thread::spawn(move || {
-        use rodio::{OutputStream, Sink, Source, source::SineWave};
+use crate::cli::{Output, PcmFormat, SoundType};
+use crate::mixer::{CHANNELS, MixState, Mixer, Track};
+use crate::pack::SoundPack;
+use crate::pcm::{PcmSink, frame_at, spawn_pcm_writer};
+use crate::routing::{Layer, Routing};
+use crate::sf2::SoundFontVoices;
+use crate::speech::{self, Word};
+use crate::synth::SynthVoice;
 
-        let (_stream, stream_handle) = match OutputStream::try_default() {
-            Ok(s) => s,
-            Err(_) => return,
-        };
+/// Sample rate used when there is no backing track to follow.
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+/// How often the audio thread checks that the output is still running.
+const WATCH_INTERVAL: Duration = Duration::from_millis(250);
+/// An output that pulls no samples for this long is treated as lost.
+const STALL_TIMEOUT: Duration = Duration::from_secs(1);
+/// Delay between attempts to reopen a failed output.
+const RETRY_INTERVAL: Duration = Duration::from_secs(2);
+
+/// A click voice: a built-in sound or one loaded at startup.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Sound {
+    Builtin(SoundType),
+    /// The `--sf2` soundfont's note for each layer.
+    Sf2,
+    /// A `[voice.<name>]` from the config, by index in `SoundBank::synths`.
+    Synth(usize),
+    /// An installed sound pack, by index in `SoundBank::packs`.
+    Pack(usize),
+    /// Marks the start and end of a break.
+    Chime,
+    /// A word of `SoundType::Count`'s counting.
+    Spoken(Word),
+    /// Count-in clicks: a sound pack's `count_in` slot, or else a built-in
+    /// voice unlike any click.
+    CountIn(Option<usize>),
+}
+
+/// Click voices loaded at startup, shared by every output.
+#[derive(Default)]
+pub struct SoundBank {
+    pub soundfont: Option<SoundFontVoices>,
+    pub synths: Vec<SynthVoice>,
+    /// Installed sound packs, each `None` until it is chosen and loaded.
+    pub packs: Vec<Option<SoundPack>>,
+}
+
+impl SoundBank {
+    fn render(&self, sound: Sound, layer: Layer, sample_rate: u32) -> Vec<f32> {
+        match sound {
+            // Heard on its own, as when choosing sounds, counting starts at one.
+            Sound::Builtin(SoundType::Count) => speech::render(Word::Number(1), layer, sample_rate),
+            Sound::Builtin(sound) => render_click(layer == Layer::Accent, sound, sample_rate),
+            Sound::Spoken(word) => speech::render(word, layer, sample_rate),
+            Sound::Sf2 => self
+                .soundfont
+                .as_ref()
+                .map(|sf| sf.render(layer, sample_rate))
+                .unwrap_or_default(),
+            Sound::Synth(i) => self
+                .synths
+                .get(i)
+                .map(|v| v.render(layer, sample_rate))
+                .unwrap_or_default(),
+            Sound::Pack(i) => self
+                .packs
+                .get(i)
+                .and_then(Option::as_ref)
+                .map(|p| p.render(layer, sample_rate))
+                .unwrap_or_default(),
+            Sound::Chime => render_chime(sample_rate),
+            Sound::CountIn(Some(i)) => self
+                .packs
+                .get(i)
+                .and_then(Option::as_ref)
+                .map(|p| p.render_count_in(sample_rate))
+                .unwrap_or_default(),
+            Sound::CountIn(None) => render_count_in(layer == Layer::Accent, sample_rate),
+        }
+    }
+}
+
+pub enum AudioMsg {
+    Click {
+        sound: Sound,
+        layer: Layer,
+    },
+    /// Start or pause the backing track at `at`.
+    Track {
+        playing: bool,
+        at: Instant,
+    },
+    /// Backing track speed, 1.0 being the original.
+    TrackSpeed(f64),
+    /// Replaces the synth voices after the config was reloaded.
+    Synths(Vec<SynthVoice>),
+    /// A sound pack loaded once chosen, for `Sound::Pack` with its index.
+    Pack(usize, SoundPack),
+}
+
+/// Reports from the audio thread to the UI.
+pub enum AudioStatus {
+    /// The output is open and playing.
+    Playing,
+    /// The output failed or was lost; the thread keeps retrying.
+    Failed(String),
+    /// A stream could not be written and has stopped for good.
+    Closed(String),
+    /// Frames per callback the opened output uses, at `sample_rate`.
+    Buffer { frames: u64, sample_rate: u32 },
+}
+
+/// Decodes an audio file to interleaved f32 samples with its channel count
+/// and sample rate.
+pub fn decode_file(path: &Path) -> Result<(Vec<f32>, u16, u32), String> {
+    let file = File::open(path).map_err(|e| format!("could not open {}: {}", path.display(), e))?;
+    let decoder = Decoder::new(BufReader::new(file))
+        .map_err(|e| format!("could not decode {}: {}", path.display(), e))?;
+    let channels = decoder.channels().max(1);
+    let sample_rate = decoder.sample_rate();
+    let samples: Vec<f32> = decoder.convert_samples::<f32>().collect();
+    if samples.is_empty() {
+        return Err(format!("{} contains no audio", path.display()));
+    }
+    Ok((samples, channels, sample_rate))
+}
+
+pub fn load_track(path: &Path) -> Result<Track, String> {
+    let (samples, channels, sample_rate) = decode_file(path)?;
+    Ok(Track::new(samples, channels, sample_rate))
+}
+
+/// Output devices of the default host, in the order `--device <index>` uses.
+pub fn output_devices() -> Result<Vec<Device>, String> {
+    cpal::default_host()
+        .output_devices()
+        .map(|devices| devices.collect())
+        .map_err(|e| format!("could not list audio devices: {}", e))
+}
 
-        let click_ms_accent: u64 = 30;
-        let click_ms_weak: u64 = 20;
-        let freq_accent: u32 = 1760;
-        let freq_weak: u32 = 1320;
+pub fn default_device() -> Option<Device> {
+    cpal::default_host().default_output_device()
+}
+
+/// Finds a device by index in `output_devices`, exact name, or a
+/// case-insensitive part of its name.
+pub fn find_device(spec: &str) -> Result<Device, String> {
+    let mut devices = output_devices()?;
+    if let Ok(index) = spec.parse::<usize>() {
+        if index < devices.len() {
+            return Ok(devices.swap_remove(index));
+        }
+        return Err(format!("no audio device with index {}", index));
+    }
+    let names: Vec<String> = devices
+        .iter()
+        .map(|d| d.name().unwrap_or_default())
+        .collect();
+    let wanted = spec.to_lowercase();
+    let pos = names
+        .iter()
+        .position(|n| n == spec)
+        .or_else(|| {
+            names
+                .iter()
+                .position(|n| n.to_lowercase().contains(&wanted))
+        })
+        .ok_or_else(|| format!("no audio device matching \"{}\" (see --list-devices)", spec))?;
+    Ok(devices.swap_remove(pos))
+}
+
+/// Name, default sample rate and channels of a device. The buffer size in
+/// use is only known once playing; see `AudioStatus::Buffer`.
+pub fn describe_device(device: &Device) -> String {
+    let name = device.name().unwrap_or_else(|_| "<unknown>".to_string());
+    match device.default_output_config() {
+        Ok(config) => format!(
+            "{} ({} Hz, {} ch)",
+            name,
+            config.sample_rate().0,
+            config.channels()
+        ),
+        Err(_) => format!("{} (no output configuration)", name),
+    }
+}
+
+/// The buffer sizes a device supports, for `--list-devices`.
+fn buffer_range(device: &Device) -> String {
+    match device.default_output_config().map(|c| *c.buffer_size()) {
+        Ok(SupportedBufferSize::Range { min, max }) => format!("buffer {}–{} frames", min, max),
+        _ => "buffer size unknown".to_string(),
+    }
+}
 
-        let sink = match Sink::try_new(&stream_handle) {
-            Ok(s) => s,
-            Err(_) => return,
+/// `--list-devices`: prints every output device with its index.
+pub fn list_devices() -> Result<(), String> {
+    let default_name = default_device().and_then(|d| d.name().ok());
+    let devices = output_devices()?;
+    if devices.is_empty() {
+        println!("No audio output devices found.");
+    }
+    for (i, device) in devices.iter().enumerate() {
+        let marker = if device.name().ok() == default_name {
+            "*"
+        } else {
+            " "
         };
-        sink.pause();
-        while let Ok((is_accent, sound)) = rx.recv() {
-            let duration_ms = if is_accent {
-                click_ms_accent
+        println!(
+            "{} {:>2}: {}, {}",
+            marker,
+            i,
+            describe_device(device),
+            buffer_range(device)
+        );
+    }
+    Ok(())
+}
+
+fn click_params(is_accent: bool, sound: SoundType) -> (u32, u64) {
+    let click_ms_accent: u64 = 30;
+    let click_ms_weak: u64 = 20;
+    let freq_accent: u32 = 1760;
+    let freq_weak: u32 = 1320;
+
+    let duration_ms = if is_accent {
+        click_ms_accent
+    } else {
+        click_ms_weak
+    };
+    let freq = match sound {
+        // Counting is spoken; see `SoundBank::render`.
+        SoundType::Click | SoundType::Count => {
+            if is_accent {
+                freq_accent
+            } else {
+                freq_weak
+            }
+        }
+        SoundType::Beep => {
+            if is_accent {
+                1760
+            } else {
+                880
+            }
+        }
+        SoundType::Wood => {
+            if is_accent {
+                1500
+            } else {
+                900
+            }
+        }
+        SoundType::Cowbell => {
+            if is_accent {
+                2000
+            } else {
+                1200
+            }
+        }
+        SoundType::Sidestick => {
+            if is_accent {
+                1200
             } else {
-                click_ms_weak
-            };
-            let freq = match sound {
-                SoundType::Click => {
-                    if is_accent {
-                        freq_accent
-                    } else {
-                        freq_weak
+                800
+            }
+        }
+    };
+    (freq, duration_ms)
+}
+
+fn render_click(is_accent: bool, sound: SoundType, sample_rate: u32) -> Vec<f32> {
+    let (freq, duration_ms) = click_params(is_accent, sound);
+    let len = (sample_rate as u64 * duration_ms / 1000) as usize;
+    let step = std::f32::consts::TAU * freq as f32 / sample_rate as f32;
+    (0..len).map(|i| (step * i as f32).sin() * 0.2).collect()
+}
+
+/// Two bell-like partials fading over a second.
+fn render_chime(sample_rate: u32) -> Vec<f32> {
+    let len = sample_rate as usize * 6 / 5;
+    let rate = sample_rate as f32;
+    (0..len)
+        .map(|i| {
+            let t = i as f32 / rate;
+            let tone = (std::f32::consts::TAU * 880.0 * t).sin()
+                + 0.5 * (std::f32::consts::TAU * 1320.0 * t).sin();
+            tone * (-t * 4.0).exp() * 0.15
+        })
+        .collect()
+}
+
+/// A low, hollow knock below every built-in click, higher on the first beat
+/// of a bar.
+fn render_count_in(is_accent: bool, sample_rate: u32) -> Vec<f32> {
+    let freq = if is_accent { 880.0 } else { 660.0 };
+    let len = sample_rate as usize * 60 / 1000;
+    let rate = sample_rate as f32;
+    (0..len)
+        .map(|i| {
+            let t = i as f32 / rate;
+            let phase = (freq * t).fract();
+            let triangle = 1.0 - 4.0 * (phase - 0.5).abs();
+            triangle * (-t * 60.0).exp() * 0.3
+        })
+        .collect()
+}
+
+/// Opens an output stream playing the shared mix and returns it with the
+/// sample rate clicks must be rendered at. The mix is made as wide as the
+/// device, so channels no layer is routed to stay silent.
+fn start_output(
+    device: Option<&Device>,
+    track_rate: Option<u32>,
+    routing: &Routing,
+    shared: &Arc<Mutex<MixState>>,
+) -> Result<(OutputStream, u32), String> {
+    let config = match device {
+        Some(device) => device.default_output_config().ok(),
+        None => default_device().and_then(|d| d.default_output_config().ok()),
+    };
+    let device_channels = config.as_ref().map(|c| c.channels()).unwrap_or(CHANNELS);
+    if device_channels < routing.channels() {
+        return Err(format!(
+            "clicks are routed to channel {} but the device has {}",
+            routing.channels(),
+            device_channels
+        ));
+    }
+    let opened = match device {
+        Some(device) => OutputStream::try_from_device(device),
+        None => OutputStream::try_default(),
+    };
+    let (stream, handle) = opened.map_err(|e| format!("could not open audio output: {}", e))?;
+
+    // Render at the device rate unless a backing track sets its own.
+    let device_rate = config.map(|c| c.sample_rate().0);
+    let sample_rate = track_rate.or(device_rate).unwrap_or(DEFAULT_SAMPLE_RATE);
+    if let Ok(mut state) = shared.lock() {
+        state.clear_voices();
+        state.set_channels(device_channels);
+    }
+    handle
+        .play_raw(Mixer::new(sample_rate, shared.clone()))
+        .map_err(|e| format!("could not start audio output: {}", e))?;
+    Ok((stream, sample_rate))
+}
+
+/// Looks a device up again by name; a handle can go stale once the device
+/// has been unplugged.
+fn reacquire_device(name: &str) -> Result<Device, String> {
+    output_devices()?
+        .into_iter()
+        .find(|d| d.name().ok().as_deref() == Some(name))
+        .ok_or_else(|| format!("{} is not connected", name))
+}
+
+/// A mix fed by the audio thread, with clicks cached at its sample rate.
+struct MixTarget {
+    shared: Arc<Mutex<MixState>>,
+    sample_rate: u32,
+    routing: Routing,
+    clicks: HashMap<(Sound, Layer), Arc<Vec<f32>>>,
+    /// Start of a stream paced by the wall clock, which lets clicks be
+    /// placed on the exact frame they were sent at.
+    clock: Option<Instant>,
+}
+
+impl MixTarget {
+    fn new(track: Option<Track>, sample_rate: u32, routing: Routing) -> Self {
+        let channels = routing.channels();
+        MixTarget {
+            shared: Arc::new(Mutex::new(MixState::new(track, channels))),
+            sample_rate,
+            routing,
+            clicks: HashMap::new(),
+            clock: None,
+        }
+    }
+
+    /// Applies `msg`; clicks are dropped unless `audible`, as they would
+    /// only play late once the output comes back.
+    fn handle(&mut self, msg: &AudioMsg, sounds: &SoundBank, audible: bool) {
+        let Ok(mut state) = self.shared.lock() else {
+            return;
+        };
+        let sample_rate = self.sample_rate;
+        let frames_rendered = state.frames_rendered();
+        // Frames into the next block at which something due at `at` plays,
+        // negative if already rendered; a stream not paced by the clock
+        // plays it at once.
+        let delay_at = |at: Instant| {
+            self.clock.map_or(0, |start| {
+                frame_at(start, at, sample_rate) as i64 - frames_rendered as i64
+            })
+        };
+        match *msg {
+            AudioMsg::Click { sound, layer } if audible => {
+                let samples = self
+                    .clicks
+                    .entry((sound, layer))
+                    .or_insert_with(|| Arc::new(sounds.render(sound, layer, sample_rate)))
+                    .clone();
+                let gains = self.routing.gains(layer, state.channels());
+                state.play(samples, gains, delay_at(Instant::now()).max(0) as usize);
+            }
+            AudioMsg::Click { .. } => {}
+            AudioMsg::Track { playing, at } => state.set_track_playing(playing, delay_at(at)),
+            AudioMsg::TrackSpeed(speed) => state.set_track_speed(speed),
+            AudioMsg::Synths(_) => self
+                .clicks
+                .retain(|(sound, _), _| !matches!(sound, Sound::Synth(_))),
+            AudioMsg::Pack(..) => {}
+        }
+    }
+}
+
+/// What the audio thread plays and where.
+pub struct AudioSetup {
+    pub track: Option<Track>,
+    pub device: Option<Device>,
+    pub output: Output,
+    /// Sample format for `Output::Stdout`.
+    pub format: PcmFormat,
+    pub routing: Routing,
+    pub sounds: SoundBank,
+    /// A file to record everything played into, as WAV.
+    pub record: Option<(File, PathBuf)>,
+}
+
+/// Plays clicks and the backing track until `rx` closes, on the audio
+/// device and/or as PCM streams to stdout and a recording. Device failures
+/// are sent on `status` and the device is reopened every `RETRY_INTERVAL`
+/// until it works again. Joining the thread finishes the streams and
+/// returns the first error writing them.
+pub fn spawn_audio_thread(
+    rx: Receiver<AudioMsg>,
+    status: Sender<AudioStatus>,
+    setup: AudioSetup,
+) -> JoinHandle<Result<(), String>> {
+    thread::spawn(move || {
+        let AudioSetup {
+            track,
+            device,
+            output,
+            format,
+            routing,
+            sounds,
+            record,
+        } = setup;
+        let mut sounds = sounds;
+        let device_name = device.as_ref().and_then(|d| d.name().ok());
+        let track_rate = track.as_ref().map(|t| t.sample_rate);
+
+        // Streams are paced by the wall clock from a common start.
+        let start = Instant::now();
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut streams = Vec::new();
+        let mut writers = Vec::new();
+        let sinks = output
+            .stdout()
+            .then_some((PcmSink::Stdout, format))
+            .into_iter()
+            .chain(record.map(|(file, path)| (PcmSink::File(file, path), PcmFormat::Wav)));
+        for (sink, format) in sinks {
+            let mut target = MixTarget::new(
+                track.as_ref().map(Track::share),
+                track_rate.unwrap_or(DEFAULT_SAMPLE_RATE),
+                routing.clone(),
+            );
+            target.clock = Some(start);
+            writers.push(spawn_pcm_writer(
+                target.shared.clone(),
+                start,
+                target.sample_rate,
+                format,
+                sink,
+                stop.clone(),
+                status.clone(),
+            ));
+            streams.push(target);
+        }
+        let mut speaker = output
+            .device()
+            .then(|| MixTarget::new(track, DEFAULT_SAMPLE_RATE, routing));
+        let mut stream: Option<OutputStream> = None;
+        let mut next_attempt = Instant::now();
+        let mut last_frames = 0;
+        let mut last_progress = Instant::now();
+        let mut buffer_reported = false;
+        loop {
+            if let Some(target) = speaker.as_mut().filter(|_| stream.is_none()) {
+                if Instant::now() >= next_attempt {
+                    let opened = match &device_name {
+                        Some(name) => reacquire_device(name).map(Some),
+                        None => Ok(device.clone()),
                     }
-                }
-                SoundType::Beep => {
-                    if is_accent {
-                        1760
-                    } else {
-                        880
+                    .and_then(|d| {
+                        start_output(d.as_ref(), track_rate, &target.routing, &target.shared)
+                    });
+                    match opened {
+                        Ok((opened, sample_rate)) => {
+                            if let Ok(state) = target.shared.lock() {
+                                last_frames = state.frames_rendered();
+                            }
+                            last_progress = Instant::now();
+                            target.sample_rate = sample_rate;
+                            target.clicks.clear();
+                            stream = Some(opened);
+                            buffer_reported = false;
+                            let _ = status.send(AudioStatus::Playing);
+                        }
+                        Err(e) => {
+                            next_attempt = Instant::now() + RETRY_INTERVAL;
+                            let _ = status.send(AudioStatus::Failed(e));
+                        }
                     }
                 }
-                SoundType::Wood => {
-                    if is_accent {
-                        1500
-                    } else {
-                        900
+            }
+
+            match rx.recv_timeout(WATCH_INTERVAL) {
+                Ok(msg) => {
+                    for target in &mut streams {
+                        target.handle(&msg, &sounds, true);
                     }
-                }
-                SoundType::Cowbell => {
-                    if is_accent {
-                        2000
-                    } else {
-                        1200
+                    if let Some(target) = speaker.as_mut() {
+                        target.handle(&msg, &sounds, stream.is_some());
                     }
-                }
-                SoundType::Sidestick => {
-                    if is_accent {
-                        1200
-                    } else {
-                        800
+                    match msg {
+                        AudioMsg::Synths(synths) => sounds.synths = synths,
+                        AudioMsg::Pack(i, pack) => {
+                            if let Some(slot) = sounds.packs.get_mut(i) {
+                                *slot = Some(pack);
+                            }
+                        }
+                        _ => {}
                     }
                 }
-            };
-            let sine = SineWave::new(freq as f32)
-                .take_duration(Duration::from_millis(duration_ms))
-                .amplify(0.2);
-            sink.append(sine);
-            sink.play();
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+
+            // A device that disappears stops pulling samples, usually
+            // without an error reaching us, so watch for that instead.
+            if let Some(target) = speaker.as_ref().filter(|_| stream.is_some()) {
+                let (frames, buffer) = target
+                    .shared
+                    .lock()
+                    .map(|s| (s.frames_rendered(), s.buffer_frames()))
+                    .unwrap_or((0, None));
+                if let Some(frames) = buffer.filter(|_| !buffer_reported) {
+                    buffer_reported = true;
+                    let _ = status.send(AudioStatus::Buffer {
+                        frames,
+                        sample_rate: target.sample_rate,
+                    });
+                }
+                if frames != last_frames {
+                    last_frames = frames;
+                    last_progress = Instant::now();
+                } else if last_progress.elapsed() >= STALL_TIMEOUT {
+                    stream = None;
+                    next_attempt = Instant::now() + RETRY_INTERVAL;
+                    let _ = status.send(AudioStatus::Failed(
+                        "audio output stopped responding".to_string(),
+                    ));
+                }
+            }
+        }
+
+        stop.store(true, Ordering::SeqCst);
+        let mut result = Ok(());
+        for writer in writers {
+            let finished = writer
+                .join()
+                .unwrap_or_else(|_| Err("audio writer panicked".to_string()));
+            result = result.and(finished);
         }
-    });
+        result
+    })
 }
@@ -1,5 +1,12 @@
+use std::path::PathBuf;
+
 use clap::{ArgAction, Parser, Subcommand, ValueEnum};
 
+use crate::tempo::{
+    parse_blocks, parse_duration_ms, parse_session_length, parse_signed_duration_ms, parse_speed,
+    parse_swing,
+};
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
 pub enum Subdivision {
     Quarter,
@@ -19,24 +26,150 @@ impl Subdivision {
     }
 }
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, ValueEnum)]
 pub enum SoundType {
     Click,
     Wood,
     Cowbell,
     Sidestick,
     Beep,
+    /// Beats counted aloud: one, two, three, with e, and, a between.
+    Count,
+}
+
+/// What to do for each click while audio output is unavailable.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+pub enum Fallback {
+    /// Ring the terminal bell.
+    Bell,
+    /// Flash the beat bar.
+    Flash,
+    Off,
+}
+
+/// Where the click stream goes.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+pub enum Output {
+    Device,
+    Stdout,
+    /// The audio device and stdout.
+    Both,
+}
+
+impl Output {
+    pub fn device(self) -> bool {
+        self != Output::Stdout
+    }
+
+    pub fn stdout(self) -> bool {
+        self != Output::Device
+    }
+}
+
+/// Sample format for `--output stdout`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+pub enum PcmFormat {
+    /// Raw signed 16-bit little-endian.
+    S16le,
+    /// Raw 32-bit float little-endian.
+    F32le,
+    /// 16-bit WAV with an open-ended length.
+    Wav,
+}
+
+/// How `metronome stats` groups sessions.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+pub enum StatsGroup {
+    Day,
+    /// Weeks starting on Monday.
+    Week,
+    Exercise,
+    /// Every session on its own.
+    Session,
 }
 
 #[derive(Subcommand, Debug)]
 pub enum Commands {
     Tap {
+        /// Start the metronome at the measured BPM instead of exiting.
         #[arg(long = "apply", action = ArgAction::SetTrue)]
         apply: bool,
+        /// Print only the rounded BPM to stdout.
+        #[arg(long = "print", action = ArgAction::SetTrue)]
+        print: bool,
+        /// Print the estimate and interval statistics as JSON.
+        #[arg(long = "json", action = ArgAction::SetTrue)]
+        json: bool,
     },
     Ramp {
         pattern: String,
     },
+    /// Tap along with <Space> and get feedback on rushing and dragging.
+    Train {
+        /// Stop after this many bars.
+        #[arg(long = "bars")]
+        bars: Option<u32>,
+        /// Write every tap offset to a CSV file.
+        #[arg(long = "save")]
+        save: Option<PathBuf>,
+    },
+    /// Click for a few bars, then keep tapping through silence.
+    Retention {
+        /// Bars played with the click before it goes silent.
+        #[arg(long = "click-bars", default_value_t = 4)]
+        click_bars: u32,
+        /// Bars to keep tapping without the click.
+        #[arg(long = "silent-bars", default_value_t = 4)]
+        silent_bars: u32,
+    },
+    /// Report timing of a recorded take against the click grid.
+    Analyze {
+        file: PathBuf,
+        /// Position of the first downbeat in the file (default: first note);
+        /// negative if the take starts after it.
+        #[arg(
+            long = "downbeat",
+            value_parser = parse_signed_duration_ms,
+            allow_hyphen_values = true
+        )]
+        downbeat: Option<i64>,
+        /// Write every detected note to a CSV file.
+        #[arg(long = "csv")]
+        csv: Option<PathBuf>,
+    },
+    /// Measure audio latency by tapping along and save it to the config.
+    Calibrate {
+        /// Bars to tap along for.
+        #[arg(long = "bars", default_value_t = 8)]
+        bars: u32,
+    },
+    /// Estimate the tempo of an audio file.
+    Detect {
+        file: PathBuf,
+        /// Start the metronome at the detected tempo and meter.
+        #[arg(long = "apply", action = ArgAction::SetTrue)]
+        apply: bool,
+    },
+    /// Show practice time per day, week and exercise from the practice log.
+    Stats {
+        /// Show one grouping; --csv and --json default to day.
+        #[arg(long = "by", value_enum)]
+        by: Option<StatsGroup>,
+        /// Only count sessions from this date on (YYYY-MM-DD).
+        #[arg(long = "since")]
+        since: Option<String>,
+        /// Print the totals as CSV.
+        #[arg(long = "csv", action = ArgAction::SetTrue, conflicts_with = "json")]
+        csv: bool,
+        /// Print the totals as JSON.
+        #[arg(long = "json", action = ArgAction::SetTrue)]
+        json: bool,
+    },
+    /// Chart the best clean tempo per day of an exercise against its target.
+    Progress {
+        /// Exercise name; without one, every exercise's best is listed.
+        name: Option<String>,
+    },
 }
 
 #[derive(Parser, Debug)]
@@ -44,21 +177,105 @@ pub enum Commands {
     name = "metronome",
     version,
     about = "A precise CLI metronome",
-    disable_help_subcommand = false
+    disable_help_subcommand = false,
+    subcommand_precedence_over_arg = true
 )]
 pub struct Cli {
     #[arg(value_parser = clap::value_parser!(u16).range(20..=400))]
     pub bpm_positional: Option<u16>,
-    #[arg(short = 'b', long = "bpm", default_value_t = 120, value_parser = clap::value_parser!(u16).range(20..=400))]
+    #[arg(short = 'b', long = "bpm", global = true, default_value_t = 120, value_parser = clap::value_parser!(u16).range(20..=400))]
     pub bpm: u16,
-    #[arg(short = 's', long = "signature", default_value = "4/4")]
+    #[arg(short = 's', long = "signature", global = true, default_value = "4/4")]
     pub signature: String,
-    #[arg(long = "subdivision", value_enum, default_value_t = Subdivision::Quarter)]
+    #[arg(long = "subdivision", global = true, value_enum, default_value_t = Subdivision::Quarter)]
     pub subdivision: Subdivision,
     #[arg(long = "mute", action = ArgAction::SetTrue)]
     pub mute: bool,
     #[arg(long = "sound", value_enum, default_value_t = SoundType::Click)]
     pub sound: SoundType,
+    #[arg(long = "tap-quantize", action = ArgAction::SetTrue)]
+    pub tap_quantize: bool,
+    /// Audio file to play along with; paused and resumed with the click.
+    #[arg(long = "play-file")]
+    pub play_file: Option<PathBuf>,
+    /// Time of the first downbeat in --play-file.
+    #[arg(long = "offset", value_parser = parse_duration_ms, default_value = "0")]
+    pub offset: u64,
+    /// Playback speed of --play-file and the click, e.g. 70% (25%–150%).
+    #[arg(long = "speed", value_parser = parse_speed, default_value = "100%")]
+    pub speed: f64,
+    /// Share of each pair of eighths or sixteenths the first one takes,
+    /// e.g. 62% (50% is straight, up to 75%).
+    #[arg(long = "swing", value_parser = parse_swing, default_value = "50%")]
+    pub swing: f64,
+    /// List audio output devices and exit.
+    #[arg(long = "list-devices", action = ArgAction::SetTrue)]
+    pub list_devices: bool,
+    /// Output device, by index from --list-devices or by name.
+    #[arg(long = "device")]
+    pub device: Option<String>,
+    /// Play on the audio device, stream to stdout, or both.
+    #[arg(long = "output", value_enum, default_value_t = Output::Device)]
+    pub output: Output,
+    /// Sample format for --output stdout.
+    #[arg(long = "format", value_enum, default_value_t = PcmFormat::S16le)]
+    pub format: PcmFormat,
+    /// SoundFont (SF2) to take the click voices from.
+    #[arg(long = "sf2")]
+    pub sf2: Option<PathBuf>,
+    /// Soundfont note of a click layer, e.g. accent=128:0:76 or sub=75@90.
+    #[arg(long = "sf2-note", value_name = "[LAYER=][BANK:]PROGRAM:KEY[@VEL]")]
+    pub sf2_note: Vec<String>,
+    /// Synth voice defined in a `[voice.NAME]` config section.
+    #[arg(long = "voice", value_name = "NAME")]
+    pub voice: Option<String>,
+    /// Sound pack installed in the config directory's `sounds/` folder.
+    #[arg(long = "sound-pack", value_name = "NAME", conflicts_with = "voice")]
+    pub sound_pack: Option<String>,
+    /// Record everything played during the session to a WAV file.
+    #[arg(long = "record")]
+    pub record: Option<PathBuf>,
+    /// Pan of a click layer, e.g. accent=left or sub=0.5; no layer means all.
+    #[arg(
+        long = "pan",
+        value_name = "[LAYER=]PAN",
+        allow_negative_numbers = true
+    )]
+    pub pan: Vec<String>,
+    /// Output channels of a click layer, e.g. 3,4 or accent=3.
+    #[arg(long = "channels", value_name = "[LAYER=]LIST")]
+    pub channels: Vec<String>,
+    /// Click to give while audio output is unavailable.
+    #[arg(long = "fallback", value_enum, default_value_t = Fallback::Bell)]
+    pub fallback: Fallback,
+    /// Name of the exercise practiced, for the practice log.
+    #[arg(long = "exercise", global = true, value_name = "NAME")]
+    pub exercise: Option<String>,
+    /// Stop after this much playing time, pauses excluded, e.g. 20m.
+    #[arg(long = "duration", value_parser = parse_session_length)]
+    pub duration: Option<u64>,
+    /// Stop after this many bars.
+    #[arg(long = "bars")]
+    pub bars: Option<u32>,
+    /// Alternate playing and breaks, e.g. 25m/5m; a chime marks each change.
+    #[arg(long = "blocks", value_parser = parse_blocks, value_name = "PLAY/BREAK")]
+    pub blocks: Option<(u64, u64)>,
+    /// Bars to count in with a distinct sound before the click starts.
+    #[arg(long = "count-in", value_name = "BARS")]
+    pub count_in: Option<u32>,
+    /// Count in again whenever playing resumes after a pause or a break.
+    #[arg(long = "count-in-resume", requires = "count_in", action = ArgAction::SetTrue)]
+    pub count_in_resume: bool,
+    /// Pick up where the last session ended (also `resume = true` in the config).
+    #[arg(long = "resume", global = true, action = ArgAction::SetTrue)]
+    pub resume: bool,
+    /// How late the click is heard, in ms (-1000 to 1000); see `metronome calibrate`.
+    #[arg(
+        long = "audio-offset-ms",
+        allow_negative_numbers = true,
+        value_parser = clap::value_parser!(i64).range(-1000..=1000)
+    )]
+    pub audio_offset_ms: Option<i64>,
     #[command(subcommand)]
     pub command: Option<Commands>,
 }
@@ -0,0 +1,176 @@
+use std::path::PathBuf;
+
+/// Settings from `~/.config/metronome/config`.
+///
+/// The file is a list of `key = value` lines; `#` at the start of a line or
+/// after whitespace starts a comment and `[name]` starts a section for
+/// settings that come in groups.
+#[derive(Default)]
+pub struct Config {
+    entries: Vec<(String, String, String)>,
+}
+
+/// `$XDG_CONFIG_HOME/metronome`, falling back to `~/.config/metronome`.
+pub fn config_dir() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("metronome"))
+}
+
+pub fn config_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join("config"))
+}
+
+/// `line` without its comment. A `#` inside a word or in quotes is kept, so
+/// values such as device names may contain one.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut after_space = true;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if after_space && !quoted => return &line[..i],
+            _ => {}
+        }
+        after_space = c.is_whitespace();
+    }
+    line
+}
+
+pub fn parse_config(text: &str) -> Result<Config, String> {
+    let mut entries = Vec::new();
+    let mut section = String::new();
+    for (i, raw) in text.lines().enumerate() {
+        let line = strip_comment(raw).trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = name.trim().to_string();
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(format!("line {}: expected `key = value`", i + 1));
+        };
+        entries.push((
+            section.clone(),
+            key.trim().to_string(),
+            value.trim().trim_matches('"').to_string(),
+        ));
+    }
+    Ok(Config { entries })
+}
+
+/// Loads the config file; a missing file is an empty config.
+pub fn load() -> Result<Config, String> {
+    let Some(path) = config_path() else {
+        return Ok(Config::default());
+    };
+    match std::fs::read_to_string(&path) {
+        Ok(text) => parse_config(&text).map_err(|e| format!("{}: {}", path.display(), e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+        Err(e) => Err(format!("could not read {}: {}", path.display(), e)),
+    }
+}
+
+/// Sets a top-level `key = value` in the config file, keeping every other
+/// line as it is, and returns the file's path.
+pub fn set_value(key: &str, value: &str) -> Result<PathBuf, String> {
+    let path = config_path().ok_or("cannot locate the config directory (HOME is not set)")?;
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(format!("could not read {}: {}", path.display(), e)),
+    };
+    let entry = format!("{} = {}", key, value);
+    let mut lines: Vec<String> = text.lines().map(String::from).collect();
+    let mut first_section = None;
+    let mut replaced = false;
+    for (i, line) in lines.iter_mut().enumerate() {
+        let content = strip_comment(line).trim();
+        if content.starts_with('[') {
+            first_section.get_or_insert(i);
+        } else if first_section.is_none()
+            && content
+                .split_once('=')
+                .is_some_and(|(k, _)| k.trim() == key)
+        {
+            *line = entry.clone();
+            replaced = true;
+        }
+    }
+    if !replaced {
+        match first_section {
+            Some(i) => lines.insert(i, entry),
+            None => lines.push(entry),
+        }
+    }
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| format!("could not create {}: {}", dir.display(), e))?;
+    }
+    let mut out = lines.join("\n");
+    out.push('\n');
+    std::fs::write(&path, out).map_err(|e| format!("could not write {}: {}", path.display(), e))?;
+    Ok(path)
+}
+
+impl Config {
+    /// A top-level setting; the last occurrence wins.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|(s, k, _)| s.is_empty() && k == key)
+            .map(|(_, _, v)| v.as_str())
+    }
+
+    /// Top-level keys, in file order.
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.entries
+            .iter()
+            .filter(|(s, _, _)| s.is_empty())
+            .map(|(_, k, _)| k.as_str())
+    }
+
+    /// Names of the sections with settings, in file order.
+    pub fn sections(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = Vec::new();
+        for (section, _, _) in &self.entries {
+            if !section.is_empty() && !names.contains(&section.as_str()) {
+                names.push(section);
+            }
+        }
+        names
+    }
+
+    /// A setting in `[section]`; the last occurrence wins.
+    pub fn get_in(&self, section: &str, key: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|(s, k, _)| s == section && k == key)
+            .map(|(_, _, v)| v.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comments_start_at_line_start_or_after_whitespace() {
+        let config = parse_config(
+            "# whole line\n\
+             device = Scarlett#2 # monitors\n\
+             sound_pack = \"kit #1\"  # quoted\n\
+             \t# indented",
+        )
+        .unwrap();
+        assert_eq!(config.get("device"), Some("Scarlett#2"));
+        assert_eq!(config.get("sound_pack"), Some("kit #1"));
+        assert_eq!(config.keys().count(), 2);
+    }
+}
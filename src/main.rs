@@ -1,15 +1,45 @@
+mod analysis;
 mod app;
 mod audio;
 mod cli;
+mod config;
+mod mixer;
+mod pack;
+mod pcm;
+mod practice;
+mod progress;
+mod routing;
+mod sf2;
+mod speech;
+mod state;
+mod stretch;
+mod synth;
 mod tap;
 mod tempo;
+mod trainer;
 mod ui;
 
 use crate::cli::Cli;
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches};
 
 fn main() {
-    println!("v0.1.2");
-    let cli = Cli::parse();
-    app::run(cli);
+    eprintln!("v0.1.2");
+    let mut command = Cli::command();
+    // List the installed packs in `--help`, next to the built-in sounds;
+    // other runs don't need to read the sounds directory.
+    if std::env::args_os().any(|a| a == "--help" || a == "-h") {
+        let packs = pack::list_packs();
+        command = command.mut_arg("sound_pack", |arg| {
+            let help = arg.get_help().map(|h| h.to_string()).unwrap_or_default();
+            let installed = if packs.is_empty() {
+                "none".to_string()
+            } else {
+                packs.join(", ")
+            };
+            arg.help(format!("{} [installed: {}]", help, installed))
+        });
+    }
+    let matches = command.get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    app::run(cli, &matches);
 }
@@ -0,0 +1,365 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rodio::Source;
+
+use crate::stretch::Stretcher;
+
+/// Channels of a backing track, which plays on the first two outputs.
+pub const CHANNELS: u16 = 2;
+/// Frames rendered per lock of the shared state.
+const BLOCK_FRAMES: usize = 64;
+/// A pause this long between blocks starts a new callback of the output.
+const CALLBACK_GAP: Duration = Duration::from_micros(300);
+/// Callbacks averaged to measure the output's buffer size.
+const MEASURED_CALLBACKS: u64 = 32;
+
+/// A one-shot mono sound being mixed in, such as a click.
+struct Voice {
+    samples: Arc<Vec<f32>>,
+    /// Level on each output channel.
+    gains: Vec<f32>,
+    pos: usize,
+    /// Frames of silence before it starts.
+    delay: usize,
+}
+
+/// A decoded audio file, interleaved with `CHANNELS` channels.
+pub struct Track {
+    pub samples: Arc<Vec<f32>>,
+    pub sample_rate: u32,
+    /// Playback position in frames.
+    pos: usize,
+    playing: bool,
+    /// A start or pause due this many frames into the next block.
+    switch: Option<(usize, bool)>,
+    speed: f64,
+    stretcher: Stretcher,
+}
+
+impl Track {
+    /// Converts interleaved samples with any channel count to the mixer layout.
+    pub fn new(samples: Vec<f32>, channels: u16, sample_rate: u32) -> Self {
+        let channels = channels.max(1) as usize;
+        let samples = if channels == CHANNELS as usize {
+            samples
+        } else {
+            samples
+                .chunks(channels)
+                .flat_map(|frame| {
+                    let left = frame[0];
+                    let right = frame.get(1).copied().unwrap_or(left);
+                    [left, right]
+                })
+                .collect()
+        };
+        Track {
+            samples: Arc::new(samples),
+            sample_rate,
+            pos: 0,
+            playing: false,
+            switch: None,
+            speed: 1.0,
+            stretcher: Stretcher::new(sample_rate),
+        }
+    }
+
+    /// Another playhead over the same samples, for a second output.
+    pub fn share(&self) -> Track {
+        Track {
+            samples: self.samples.clone(),
+            sample_rate: self.sample_rate,
+            pos: self.pos,
+            playing: self.playing,
+            switch: self.switch,
+            speed: self.speed,
+            stretcher: Stretcher::new(self.sample_rate),
+        }
+    }
+
+    fn frames(&self) -> usize {
+        self.samples.len() / CHANNELS as usize
+    }
+
+    /// The frames of the next `frames` the track plays in, starting or
+    /// pausing it on the frame it is due.
+    fn span(&mut self, frames: usize) -> (usize, usize) {
+        let was_playing = self.playing;
+        match self.switch.take() {
+            Some((at, playing)) if at < frames => {
+                self.playing = playing;
+                match (was_playing, playing) {
+                    (true, true) => (0, frames),
+                    (false, true) => (at, frames),
+                    (true, false) => (0, at),
+                    (false, false) => (0, 0),
+                }
+            }
+            switch => {
+                self.switch = switch.map(|(at, playing)| (at - frames, playing));
+                if was_playing { (0, frames) } else { (0, 0) }
+            }
+        }
+    }
+}
+
+/// State shared between the audio thread and the mixer source.
+#[derive(Default)]
+pub struct MixState {
+    voices: Vec<Voice>,
+    track: Option<Track>,
+    channels: u16,
+    /// The track's stereo frames before they are spread over `channels`.
+    track_buf: Vec<f32>,
+    /// Frames pulled by the output so far; stops growing if the device dies.
+    frames_rendered: u64,
+    /// Frames the output pulls per callback, once measured.
+    buffer_frames: Option<u64>,
+}
+
+impl MixState {
+    pub fn new(track: Option<Track>, channels: u16) -> Self {
+        MixState {
+            track,
+            channels: channels.max(CHANNELS),
+            ..Default::default()
+        }
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    /// Changes the mix width; call with no voices playing.
+    pub fn set_channels(&mut self, channels: u16) {
+        self.channels = channels.max(CHANNELS);
+    }
+
+    /// Starts a sound `delay` frames into the next block rendered, at
+    /// `gains` per channel.
+    pub fn play(&mut self, samples: Arc<Vec<f32>>, gains: Vec<f32>, delay: usize) {
+        self.voices.push(Voice {
+            samples,
+            gains,
+            pos: 0,
+            delay,
+        });
+    }
+
+    /// Starts or pauses the track `delay` frames into the next block
+    /// rendered, like a sound passed to `play`. A negative delay is how long
+    /// ago that was; the track's position is moved to where it would be had
+    /// the change come in time.
+    pub fn set_track_playing(&mut self, playing: bool, delay: i64) {
+        let Some(track) = self.track.as_mut() else {
+            return;
+        };
+        if let Some((_, pending)) = track.switch.take() {
+            track.playing = pending;
+        }
+        if delay >= 0 {
+            track.switch = Some((delay as usize, playing));
+            return;
+        }
+        let late = (delay.unsigned_abs() as f64 * track.speed) as usize;
+        match (track.playing, playing) {
+            (true, false) => track.pos = track.pos.saturating_sub(late),
+            (false, true) => track.pos = (track.pos + late).min(track.frames()),
+            _ => {}
+        }
+        track.playing = playing;
+        track.stretcher.reset(track.pos);
+    }
+
+    /// Playback speed of the track; 1.0 is the original tempo and pitch.
+    pub fn set_track_speed(&mut self, speed: f64) {
+        if let Some(track) = self.track.as_mut() {
+            track.speed = speed;
+            track.stretcher.reset(track.pos);
+        }
+    }
+
+    /// Drops clicks still sounding, e.g. ones rendered for another device.
+    pub fn clear_voices(&mut self) {
+        self.voices.clear();
+    }
+
+    pub fn frames_rendered(&self) -> u64 {
+        self.frames_rendered
+    }
+
+    /// The buffer size the output actually uses, which the audio backend
+    /// picks and does not report.
+    pub fn buffer_frames(&self) -> Option<u64> {
+        self.buffer_frames
+    }
+
+    fn render(&mut self, out: &mut [f32]) {
+        out.iter_mut().for_each(|s| *s = 0.0);
+        let channels = self.channels as usize;
+        let frames = out.len() / channels;
+        self.frames_rendered += frames as u64;
+        let span = self.track.as_mut().map_or((0, 0), |t| t.span(frames));
+        if let Some(track) = self.track.as_mut().filter(|_| span.0 < span.1) {
+            let ch = CHANNELS as usize;
+            let buf = &mut self.track_buf;
+            buf.clear();
+            buf.resize((span.1 - span.0) * ch, 0.0);
+            if (track.speed - 1.0).abs() > f64::EPSILON {
+                track.stretcher.fill(&track.samples, track.speed, buf);
+                track.pos = track.stretcher.position();
+            } else {
+                let start = (track.pos * ch).min(track.samples.len());
+                let n = buf.len().min(track.samples.len() - start);
+                buf[..n].copy_from_slice(&track.samples[start..start + n]);
+                track.pos += n / ch;
+            }
+            let frames_out = out.chunks_mut(channels).skip(span.0);
+            for (frame, src) in frames_out.zip(buf.chunks(ch)) {
+                frame[..ch].copy_from_slice(src);
+            }
+        }
+        for voice in &mut self.voices {
+            for frame in out.chunks_mut(channels) {
+                if voice.delay > 0 {
+                    voice.delay -= 1;
+                    continue;
+                }
+                let Some(&s) = voice.samples.get(voice.pos) else {
+                    break;
+                };
+                for (o, g) in frame.iter_mut().zip(&voice.gains) {
+                    *o += s * g;
+                }
+                voice.pos += 1;
+            }
+        }
+        self.voices.retain(|v| v.pos < v.samples.len());
+    }
+}
+
+/// Endless rodio source mixing clicks over an optional backing track.
+pub struct Mixer {
+    shared: Arc<Mutex<MixState>>,
+    sample_rate: u32,
+    channels: u16,
+    block: Vec<f32>,
+    pos: usize,
+    meter: BufferMeter,
+}
+
+/// Measures the output's buffer size from when blocks are rendered: blocks
+/// rendered close together belong to one callback.
+#[derive(Default)]
+struct BufferMeter {
+    last_block: Option<Instant>,
+    callbacks: u64,
+    frames: u64,
+}
+
+impl BufferMeter {
+    /// Counts the frames of the first whole callbacks; a block left over
+    /// from one callback evens out over many.
+    fn block(&mut self, state: &mut MixState) {
+        if self.callbacks > MEASURED_CALLBACKS {
+            return;
+        }
+        let now = Instant::now();
+        if self
+            .last_block
+            .is_some_and(|last| now.duration_since(last) >= CALLBACK_GAP)
+        {
+            self.callbacks += 1;
+            if self.callbacks == MEASURED_CALLBACKS + 1 {
+                state.buffer_frames = Some(self.frames / MEASURED_CALLBACKS);
+            }
+        }
+        if (1..=MEASURED_CALLBACKS).contains(&self.callbacks) {
+            self.frames += BLOCK_FRAMES as u64;
+        }
+        self.last_block = Some(now);
+    }
+}
+
+impl Mixer {
+    /// A source as wide as the mix in `shared` is now.
+    pub fn new(sample_rate: u32, shared: Arc<Mutex<MixState>>) -> Self {
+        let channels = shared
+            .lock()
+            .map(|mut s| {
+                s.buffer_frames = None;
+                s.channels
+            })
+            .unwrap_or(CHANNELS);
+        let len = BLOCK_FRAMES * channels as usize;
+        Mixer {
+            shared,
+            sample_rate,
+            channels,
+            block: vec![0.0; len],
+            pos: len,
+            meter: BufferMeter::default(),
+        }
+    }
+}
+
+impl Iterator for Mixer {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.pos >= self.block.len() {
+            if let Ok(mut state) = self.shared.lock() {
+                self.meter.block(&mut state);
+                state.render(&mut self.block);
+            }
+            self.pos = 0;
+        }
+        let s = self.block[self.pos];
+        self.pos += 1;
+        Some(s)
+    }
+}
+
+impl Source for Mixer {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clicks_mix_at_their_gains_and_delays() {
+        let mut state = MixState::new(None, 3);
+        state.play(Arc::new(vec![1.0, 0.5]), vec![1.0, 0.0, 0.5], 0);
+        state.play(Arc::new(vec![1.0]), vec![0.0, 1.0, 1.0], 1);
+        let mut out = vec![0.0; BLOCK_FRAMES * 3];
+        state.render(&mut out);
+        assert_eq!(out[..9], [1.0, 0.0, 0.5, 0.5, 1.0, 1.25, 0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn track_stays_on_the_first_two_channels() {
+        let track = Track::new(vec![0.25, 0.5], 2, 44100);
+        let mut state = MixState::new(Some(track), 4);
+        state.set_track_playing(true, 0);
+        state.play(Arc::new(vec![1.0]), vec![0.0, 0.0, 1.0, 1.0], 0);
+        let mut out = vec![0.0; BLOCK_FRAMES * 4];
+        state.render(&mut out);
+        assert_eq!(out[..4], [0.25, 0.5, 1.0, 1.0]);
+    }
+}
@@ -0,0 +1,193 @@
+use std::path::{Path, PathBuf};
+
+use crate::audio::decode_file;
+use crate::config::{self, parse_config};
+use crate::routing::Layer;
+
+/// File in a pack directory that maps slots to samples.
+const MANIFEST: &str = "manifest";
+
+/// Slots a manifest can fill, with the slot each one falls back to.
+const SLOTS: [(&str, Option<&str>); 4] = [
+    ("beat", None),
+    ("accent", Some("beat")),
+    ("subdivision", Some("beat")),
+    ("count_in", Some("accent")),
+];
+
+/// A mono sample at its own rate.
+struct Sample {
+    data: Vec<f32>,
+    sample_rate: u32,
+}
+
+/// Click samples from `~/.config/metronome/sounds/<name>/`.
+pub struct SoundPack {
+    pub name: String,
+    /// Every slot in `SLOTS` order, missing ones filled from their fallback.
+    slots: Vec<(&'static str, Sample)>,
+}
+
+/// `$XDG_CONFIG_HOME/metronome/sounds`, where packs are installed.
+pub fn packs_dir() -> Option<PathBuf> {
+    config::config_dir().map(|dir| dir.join("sounds"))
+}
+
+/// Names of the installed packs, sorted; any directory counts.
+pub fn list_packs() -> Vec<String> {
+    let Some(entries) = packs_dir().and_then(|dir| std::fs::read_dir(dir).ok()) else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = entries
+        .flatten()
+        .filter(|e| e.path().is_dir())
+        .filter_map(|e| e.file_name().into_string().ok())
+        .collect();
+    names.sort();
+    names
+}
+
+fn load_sample(dir: &Path, file: &str) -> Result<Sample, String> {
+    let (samples, channels, sample_rate) = decode_file(&dir.join(file))?;
+    // Mix down to mono; routing places it on the output channels.
+    let data = samples
+        .chunks(channels as usize)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect();
+    Ok(Sample { data, sample_rate })
+}
+
+/// Linear interpolation to `to` Hz; clicks are short enough for that.
+fn resample(sample: &Sample, to: u32) -> Vec<f32> {
+    if sample.sample_rate == to {
+        return sample.data.clone();
+    }
+    let step = sample.sample_rate as f64 / to as f64;
+    let len = (sample.data.len() as f64 / step) as usize;
+    (0..len)
+        .map(|i| {
+            let pos = i as f64 * step;
+            let at = pos as usize;
+            let frac = (pos - at as f64) as f32;
+            let a = sample.data[at];
+            let b = sample.data.get(at + 1).copied().unwrap_or(0.0);
+            a + (b - a) * frac
+        })
+        .collect()
+}
+
+impl SoundPack {
+    /// Loads the pack called `name`, reading its manifest and decoding
+    /// every sample it names.
+    pub fn load(name: &str) -> Result<SoundPack, String> {
+        // A name picks a directory inside the packs folder, never a path.
+        if name.is_empty() || name == "." || name.contains("..") || name.contains(['/', '\\']) {
+            return Err(format!("invalid sound pack name \"{}\"", name));
+        }
+        let dir = packs_dir()
+            .ok_or("cannot locate the config directory (HOME is not set)")?
+            .join(name);
+        if !dir.is_dir() {
+            let installed = list_packs();
+            return Err(format!(
+                "no sound pack \"{}\" in {} (installed: {})",
+                name,
+                dir.parent().unwrap_or(&dir).display(),
+                if installed.is_empty() {
+                    "none".to_string()
+                } else {
+                    installed.join(", ")
+                }
+            ));
+        }
+        let path = dir.join(MANIFEST);
+        let text = std::fs::read_to_string(&path).map_err(|e| {
+            format!(
+                "sound pack \"{}\": could not read {}: {}",
+                name,
+                path.display(),
+                e
+            )
+        })?;
+        let manifest = parse_config(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+        if let Some(section) = manifest.sections().first() {
+            return Err(format!(
+                "{}: unexpected section [{}]",
+                path.display(),
+                section
+            ));
+        }
+        for key in manifest.keys() {
+            if !SLOTS.iter().any(|(slot, _)| *slot == key) {
+                return Err(format!(
+                    "{}: unknown slot \"{}\" (beat, accent, subdivision, count_in)",
+                    path.display(),
+                    key
+                ));
+            }
+        }
+        let mut slots: Vec<(&'static str, Sample)> = Vec::new();
+        for (slot, fallback) in SLOTS {
+            let sample = match (manifest.get(slot), fallback) {
+                (Some(file), _) => load_sample(&dir, file)
+                    .map_err(|e| format!("sound pack \"{}\", {}: {}", name, slot, e))?,
+                (None, Some(fallback)) => {
+                    let (_, s) = slots.iter().find(|(s, _)| *s == fallback).unwrap();
+                    Sample {
+                        data: s.data.clone(),
+                        sample_rate: s.sample_rate,
+                    }
+                }
+                (None, None) => {
+                    return Err(format!(
+                        "{}: no file for the {} slot, e.g. `{} = {}.wav`",
+                        path.display(),
+                        slot,
+                        slot,
+                        slot
+                    ));
+                }
+            };
+            slots.push((slot, sample));
+        }
+        Ok(SoundPack {
+            name: name.to_string(),
+            slots,
+        })
+    }
+
+    fn sample(&self, slot: &str) -> &Sample {
+        &self.slots.iter().find(|(s, _)| *s == slot).unwrap().1
+    }
+
+    pub fn render(&self, layer: Layer, sample_rate: u32) -> Vec<f32> {
+        let slot = match layer {
+            Layer::Accent => "accent",
+            Layer::Beat => "beat",
+            Layer::Sub => "subdivision",
+        };
+        resample(self.sample(slot), sample_rate)
+    }
+
+    pub fn render_count_in(&self, sample_rate: u32) -> Vec<f32> {
+        resample(self.sample("count_in"), sample_rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_cannot_leave_the_packs_directory() {
+        for name in ["", ".", "..", "../other", "a/b", "a\\b", "/etc", "x/../y"] {
+            let err = SoundPack::load(name).err().unwrap();
+            assert!(
+                err.starts_with("invalid sound pack name"),
+                "{}: {}",
+                name,
+                err
+            );
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use rodio::Source;
+
+use crate::audio::AudioStatus;
+use crate::cli::PcmFormat;
+use crate::mixer::{MixState, Mixer};
+
+/// How far ahead of the wall clock the stream is written, so a reader
+/// never runs dry between wake-ups.
+const LEAD: Duration = Duration::from_millis(20);
+/// Sleep between writes.
+const WRITE_INTERVAL: Duration = Duration::from_millis(5);
+/// How often a recorded WAV header is brought up to date, so a session
+/// that ends without finishing the file still leaves it readable.
+const HEADER_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Frame of a stream started at `start` that should be written by now.
+pub fn due_frame(start: Instant, sample_rate: u32) -> u64 {
+    frame_at(start, Instant::now(), sample_rate)
+}
+
+/// Frame of a stream started at `start` that is written at `at`.
+pub fn frame_at(start: Instant, at: Instant, sample_rate: u32) -> u64 {
+    ((at.saturating_duration_since(start) + LEAD).as_secs_f64() * sample_rate as f64) as u64
+}
+
+/// WAV header for a 16-bit stream of unknown length; the size fields are
+/// left at their maximum as readers of piped WAV expect.
+fn wav_header(sample_rate: u32, channels: u16) -> Vec<u8> {
+    let block_align = channels * 2;
+    let mut header = Vec::with_capacity(44);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&u32::MAX.to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&channels.to_le_bytes());
+    header.extend_from_slice(&sample_rate.to_le_bytes());
+    header.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    header.extend_from_slice(&block_align.to_le_bytes());
+    header.extend_from_slice(&16u16.to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&u32::MAX.to_le_bytes());
+    header
+}
+
+fn encode(sample: f32, format: PcmFormat, out: &mut Vec<u8>) {
+    match format {
+        PcmFormat::S16le | PcmFormat::Wav => {
+            let s = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            out.extend_from_slice(&s.to_le_bytes());
+        }
+        PcmFormat::F32le => out.extend_from_slice(&sample.to_le_bytes()),
+    }
+}
+
+/// Where a PCM stream goes.
+pub enum PcmSink {
+    Stdout,
+    /// A file being recorded; a WAV header gets its real sizes on close.
+    File(File, PathBuf),
+}
+
+impl PcmSink {
+    fn write(&mut self, buf: &[u8]) -> Result<(), String> {
+        match self {
+            PcmSink::Stdout => {
+                let mut out = std::io::stdout().lock();
+                out.write_all(buf)
+                    .and_then(|_| out.flush())
+                    .map_err(|e| match e.kind() {
+                        std::io::ErrorKind::BrokenPipe => "stdout closed".to_string(),
+                        _ => format!("could not write to stdout: {}", e),
+                    })
+            }
+            PcmSink::File(file, path) => file
+                .write_all(buf)
+                .map_err(|e| format!("could not write {}: {}", path.display(), e)),
+        }
+    }
+
+    /// Fills in the RIFF and data chunk sizes of a recorded WAV file for
+    /// what is written so far; writing carries on at the end.
+    fn update_wav_sizes(&mut self, data_len: u64) -> Result<(), String> {
+        let PcmSink::File(file, path) = self else {
+            return Ok(());
+        };
+        let data_len = data_len.min(u32::MAX as u64 - 36) as u32;
+        let patch = |file: &mut File, at: u64, value: u32| {
+            file.seek(SeekFrom::Start(at))?;
+            file.write_all(&value.to_le_bytes())
+        };
+        patch(file, 4, 36 + data_len)
+            .and_then(|_| patch(file, 40, data_len))
+            .and_then(|_| file.seek(SeekFrom::End(0)).map(|_| ()))
+            .map_err(|e| format!("could not write {}: {}", path.display(), e))
+    }
+}
+
+/// Streams the mix in `shared` to `sink` in real time, frame 0 being at
+/// `start`, until `stop` is set or writing fails. A failure ends the stream;
+/// it is reported on `status` and returned when the thread is joined.
+pub fn spawn_pcm_writer(
+    shared: Arc<Mutex<MixState>>,
+    start: Instant,
+    sample_rate: u32,
+    format: PcmFormat,
+    mut sink: PcmSink,
+    stop: Arc<AtomicBool>,
+    status: Sender<AudioStatus>,
+) -> JoinHandle<Result<(), String>> {
+    thread::spawn(move || {
+        let mut mixer = Mixer::new(sample_rate, shared);
+        let channels = mixer.channels();
+        let mut buf = if format == PcmFormat::Wav {
+            wav_header(sample_rate, channels)
+        } else {
+            Vec::new()
+        };
+        let header_len = buf.len() as u64;
+        let mut bytes: u64 = 0;
+        let mut written: u64 = 0;
+        let mut header_updated = Instant::now();
+        loop {
+            // Read the flag first so the last pass writes up to now.
+            let stopping = stop.load(Ordering::SeqCst);
+            let due = due_frame(start, sample_rate);
+            for _ in written..due {
+                for _ in 0..channels {
+                    encode(mixer.next().unwrap_or(0.0), format, &mut buf);
+                }
+            }
+            written = written.max(due);
+            let mut result = sink.write(&buf);
+            bytes += buf.len() as u64;
+            buf.clear();
+            if format == PcmFormat::Wav && header_updated.elapsed() >= HEADER_INTERVAL {
+                header_updated = Instant::now();
+                result = result.and_then(|_| sink.update_wav_sizes(bytes - header_len));
+            }
+            if let Err(e) = result {
+                let _ = status.send(AudioStatus::Closed(e.clone()));
+                return Err(e);
+            }
+            if stopping {
+                break;
+            }
+            thread::sleep(WRITE_INTERVAL);
+        }
+        if format == PcmFormat::Wav {
+            sink.update_wav_sizes(bytes - header_len)?;
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,657 @@
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+
+use crate::cli::StatsGroup;
+use crate::config;
+
+const HEADER: &str = "start,end,playing_secs,exercise,signatures,bpm_secs";
+
+/// Output format of `metronome stats`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum StatsFormat {
+    Table,
+    Csv,
+    Json,
+}
+
+/// `$XDG_CONFIG_HOME/metronome/practice.csv`, one line per session.
+pub fn log_path() -> Option<PathBuf> {
+    config::config_dir().map(|dir| dir.join("practice.csv"))
+}
+
+/// Seconds the local time zone, from `TZ` or the system setting, is ahead
+/// of UTC at `unix_secs`; UTC when the zone cannot be read.
+fn utc_offset_secs(unix_secs: i64) -> i64 {
+    let zone = match std::env::var("TZ") {
+        Ok(tz) if !tz.is_empty() => tz::TimeZone::from_posix_tz(tz.trim_start_matches(':')),
+        _ => tz::TimeZone::local(),
+    };
+    zone.ok()
+        .and_then(|zone| {
+            zone.find_local_time_type(unix_secs)
+                .ok()
+                .map(|t| t.ut_offset() as i64)
+        })
+        .unwrap_or(0)
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date.
+fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (m as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    (
+        if m <= 2 {
+            yoe + era * 400 + 1
+        } else {
+            yoe + era * 400
+        },
+        m,
+        d,
+    )
+}
+
+/// Local time as `2024-03-05T19:02:11+01:00`.
+pub fn format_timestamp(time: SystemTime) -> String {
+    let unix = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    let offset = utc_offset_secs(unix);
+    let local = unix + offset;
+    let (y, m, d) = civil_from_days(local.div_euclid(86_400));
+    let secs = local.rem_euclid(86_400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}{}{:02}:{:02}",
+        y,
+        m,
+        d,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        if offset < 0 { '-' } else { '+' },
+        offset.abs() / 3600,
+        offset.abs() / 60 % 60
+    )
+}
+
+/// Days since 1970-01-01 of a `YYYY-MM-DD` date; impossible dates such as
+/// `2024-02-31` are rejected.
+pub fn parse_date(s: &str) -> Option<i64> {
+    let mut parts = s.get(..10)?.split('-');
+    let y = parts.next()?.parse().ok()?;
+    let m = parts
+        .next()?
+        .parse()
+        .ok()
+        .filter(|m| (1..=12).contains(m))?;
+    let d = parts
+        .next()?
+        .parse()
+        .ok()
+        .filter(|d| (1..=31).contains(d))?;
+    let days = days_from_civil(y, m, d);
+    (civil_from_days(days) == (y, m, d)).then_some(days)
+}
+
+pub fn format_date(days: i64) -> String {
+    let (y, m, d) = civil_from_days(days);
+    format!("{:04}-{:02}-{:02}", y, m, d)
+}
+
+pub fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+pub fn split_csv(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().unwrap().push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// `1h 05m`, `12m 30s`.
+fn format_duration(secs: f64) -> String {
+    let secs = secs.round() as u64;
+    if secs >= 3600 {
+        format!("{}h {:02}m", secs / 3600, secs / 60 % 60)
+    } else {
+        format!("{}m {:02}s", secs / 60, secs % 60)
+    }
+}
+
+/// Time spent at each value, e.g. `120:90.5 126:30.0`.
+fn format_times<K: std::fmt::Display>(times: impl Iterator<Item = (K, f64)>) -> String {
+    times
+        .map(|(k, secs)| format!("{}:{:.1}", k, secs))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The session being practiced, appended to the log when it ends.
+pub struct PracticeSession {
+    start: SystemTime,
+    exercise: String,
+    last: Instant,
+    played: Duration,
+    bpm: BTreeMap<u16, Duration>,
+    signatures: Vec<(String, Duration)>,
+}
+
+impl PracticeSession {
+    pub fn new(exercise: Option<&str>) -> Self {
+        PracticeSession {
+            start: SystemTime::now(),
+            exercise: exercise.unwrap_or_default().to_string(),
+            last: Instant::now(),
+            played: Duration::ZERO,
+            bpm: BTreeMap::new(),
+            signatures: Vec::new(),
+        }
+    }
+
+    /// Counts the time since the last update as played at `bpm` in
+    /// `signature`, unless paused.
+    pub fn update(&mut self, playing: bool, bpm: u16, signature: &str) {
+        let now = Instant::now();
+        let dt = now - self.last;
+        self.last = now;
+        if !playing {
+            return;
+        }
+        self.played += dt;
+        *self.bpm.entry(bpm).or_default() += dt;
+        match self.signatures.iter_mut().find(|(s, _)| s == signature) {
+            Some((_, time)) => *time += dt,
+            None => self.signatures.push((signature.to_string(), dt)),
+        }
+    }
+
+    pub fn played(&self) -> Duration {
+        self.played
+    }
+
+    /// Appends the session to the practice log and returns its path.
+    pub fn save(&self) -> Result<PathBuf, String> {
+        let path = log_path().ok_or("cannot locate the config directory (HOME is not set)")?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| format!("could not create {}: {}", dir.display(), e))?;
+        }
+        let line = format!(
+            "{},{},{:.1},{},{},{}\n",
+            format_timestamp(self.start),
+            format_timestamp(SystemTime::now()),
+            self.played.as_secs_f64(),
+            csv_field(&self.exercise),
+            format_times(self.signatures.iter().map(|(s, t)| (s, t.as_secs_f64()))),
+            format_times(self.bpm.iter().map(|(b, t)| (b, t.as_secs_f64())))
+        );
+        let new = !path.exists();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| format!("could not open {}: {}", path.display(), e))?;
+        let text = if new {
+            format!("{}\n{}", HEADER, line)
+        } else {
+            line
+        };
+        file.write_all(text.as_bytes())
+            .map_err(|e| format!("could not write {}: {}", path.display(), e))?;
+        Ok(path)
+    }
+}
+
+/// A session read back from the log.
+pub struct LoggedSession {
+    pub start: String,
+    pub end: String,
+    pub playing_secs: f64,
+    pub exercise: String,
+    pub signatures: Vec<(String, f64)>,
+    pub bpm: Vec<(u16, f64)>,
+    /// Local date the session started on, in days since 1970-01-01.
+    pub day: i64,
+}
+
+fn parse_times<K: std::str::FromStr>(s: &str) -> Option<Vec<(K, f64)>> {
+    s.split_whitespace()
+        .map(|pair| {
+            let (k, secs) = pair.rsplit_once(':')?;
+            Some((k.parse().ok()?, secs.parse().ok()?))
+        })
+        .collect()
+}
+
+fn parse_session(line: &str) -> Option<LoggedSession> {
+    let fields = split_csv(line);
+    let [start, end, playing, exercise, signatures, bpm] = fields.as_slice() else {
+        return None;
+    };
+    Some(LoggedSession {
+        day: parse_date(start)?,
+        start: start.clone(),
+        end: end.clone(),
+        playing_secs: playing.parse().ok()?,
+        exercise: exercise.clone(),
+        signatures: parse_times(signatures)?,
+        bpm: parse_times(bpm)?,
+    })
+}
+
+/// Every session in the log; a missing log is empty. Damaged lines are
+/// reported on stderr and skipped.
+pub fn read_log() -> Result<Vec<LoggedSession>, String> {
+    let Some(path) = log_path() else {
+        return Ok(Vec::new());
+    };
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("could not read {}: {}", path.display(), e)),
+    };
+    let mut sessions = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() || line == HEADER {
+            continue;
+        }
+        match parse_session(line) {
+            Some(session) => sessions.push(session),
+            None => eprintln!(
+                "warning: {}: skipping unreadable line {}",
+                path.display(),
+                i + 1
+            ),
+        }
+    }
+    Ok(sessions)
+}
+
+/// Practice time of one day, week or exercise.
+struct Totals {
+    key: String,
+    sessions: u32,
+    secs: f64,
+    bpm: BTreeMap<u16, f64>,
+}
+
+impl Totals {
+    fn add(&mut self, session: &LoggedSession) {
+        self.sessions += 1;
+        self.secs += session.playing_secs;
+        for &(bpm, secs) in &session.bpm {
+            *self.bpm.entry(bpm).or_default() += secs;
+        }
+    }
+
+    /// Time-weighted mean tempo, then the slowest and fastest.
+    fn bpm_summary(&self) -> Option<(f64, u16, u16)> {
+        let total: f64 = self.bpm.values().sum();
+        if total <= 0.0 {
+            return None;
+        }
+        let mean = self.bpm.iter().map(|(&b, &s)| b as f64 * s).sum::<f64>() / total;
+        Some((
+            mean,
+            *self.bpm.keys().next()?,
+            *self.bpm.keys().next_back()?,
+        ))
+    }
+}
+
+fn group_key(session: &LoggedSession, by: StatsGroup) -> String {
+    match by {
+        StatsGroup::Day => format_date(session.day),
+        // 1970-01-01 was a Thursday.
+        StatsGroup::Week => format_date(session.day - (session.day + 3).rem_euclid(7)),
+        StatsGroup::Exercise if session.exercise.is_empty() => "(none)".to_string(),
+        StatsGroup::Exercise => session.exercise.clone(),
+        StatsGroup::Session => session.start.clone(),
+    }
+}
+
+fn group(sessions: &[LoggedSession], by: StatsGroup) -> Vec<Totals> {
+    let mut groups: Vec<Totals> = Vec::new();
+    for session in sessions {
+        let key = group_key(session, by);
+        let index = match groups.iter().position(|g| g.key == key) {
+            Some(i) => i,
+            None => {
+                groups.push(Totals {
+                    key,
+                    sessions: 0,
+                    secs: 0.0,
+                    bpm: BTreeMap::new(),
+                });
+                groups.len() - 1
+            }
+        };
+        groups[index].add(session);
+    }
+    if by != StatsGroup::Exercise {
+        groups.sort_by(|a, b| a.key.cmp(&b.key));
+    }
+    groups
+}
+
+fn table(title: &str, groups: &[Totals]) -> String {
+    let width = groups
+        .iter()
+        .map(|g| g.key.chars().count())
+        .max()
+        .unwrap_or(0);
+    let width = width.max(title.len());
+    let mut out = format!(
+        "{:<width$}  sessions      time  avg bpm  range\n",
+        title,
+        width = width
+    );
+    for g in groups {
+        let (avg, range) = match g.bpm_summary() {
+            Some((mean, lo, hi)) => (format!("{:.0}", mean), format!("{}–{}", lo, hi)),
+            None => ("--".to_string(), "--".to_string()),
+        };
+        out.push_str(&format!(
+            "{:<width$}  {:>8}  {:>8}  {:>7}  {}\n",
+            g.key,
+            g.sessions,
+            format_duration(g.secs),
+            avg,
+            range,
+            width = width
+        ));
+    }
+    out
+}
+
+fn totals_csv(by: StatsGroup, groups: &[Totals]) -> String {
+    let name = by.to_possible_value().map(|v| v.get_name().to_string());
+    let mut out = format!(
+        "{},sessions,playing_secs,avg_bpm,min_bpm,max_bpm\n",
+        name.unwrap_or_default()
+    );
+    for g in groups {
+        let bpm = match g.bpm_summary() {
+            Some((mean, lo, hi)) => format!("{:.1},{},{}", mean, lo, hi),
+            None => ",,".to_string(),
+        };
+        out.push_str(&format!(
+            "{},{},{:.1},{}\n",
+            csv_field(&g.key),
+            g.sessions,
+            g.secs,
+            bpm
+        ));
+    }
+    out
+}
+
+fn totals_json(by: StatsGroup, groups: &[Totals]) -> String {
+    let name = by.to_possible_value().map(|v| v.get_name().to_string());
+    let rows: Vec<String> = groups
+        .iter()
+        .map(|g| {
+            let bpm = match g.bpm_summary() {
+                Some((mean, lo, hi)) => format!(
+                    "\"avg_bpm\":{:.1},\"min_bpm\":{},\"max_bpm\":{}",
+                    mean, lo, hi
+                ),
+                None => "\"avg_bpm\":null,\"min_bpm\":null,\"max_bpm\":null".to_string(),
+            };
+            format!(
+                "{{\"{}\":{},\"sessions\":{},\"playing_secs\":{:.1},{}}}",
+                name.as_deref().unwrap_or_default(),
+                json_string(&g.key),
+                g.sessions,
+                g.secs,
+                bpm
+            )
+        })
+        .collect();
+    format!("[{}]\n", rows.join(","))
+}
+
+fn sessions_csv(sessions: &[LoggedSession]) -> String {
+    let mut out = format!("{}\n", HEADER);
+    for s in sessions {
+        out.push_str(&format!(
+            "{},{},{:.1},{},{},{}\n",
+            s.start,
+            s.end,
+            s.playing_secs,
+            csv_field(&s.exercise),
+            format_times(s.signatures.iter().map(|(k, t)| (k, *t))),
+            format_times(s.bpm.iter().map(|(k, t)| (k, *t)))
+        ));
+    }
+    out
+}
+
+fn sessions_json(sessions: &[LoggedSession]) -> String {
+    let object = |times: Vec<String>| format!("{{{}}}", times.join(","));
+    let rows: Vec<String> = sessions
+        .iter()
+        .map(|s| {
+            format!(
+                "{{\"start\":{},\"end\":{},\"playing_secs\":{:.1},\"exercise\":{},\"signatures\":{},\"bpm_secs\":{}}}",
+                json_string(&s.start),
+                json_string(&s.end),
+                s.playing_secs,
+                json_string(&s.exercise),
+                object(
+                    s.signatures
+                        .iter()
+                        .map(|(k, t)| format!("{}:{:.1}", json_string(k), t))
+                        .collect()
+                ),
+                object(
+                    s.bpm
+                        .iter()
+                        .map(|(k, t)| format!("\"{}\":{:.1}", k, t))
+                        .collect()
+                )
+            )
+        })
+        .collect();
+    format!("[{}]\n", rows.join(","))
+}
+
+/// `metronome stats`: practice time from the log, as tables or exported
+/// as CSV or JSON.
+pub fn run_stats(
+    by: Option<StatsGroup>,
+    since: Option<&str>,
+    format: StatsFormat,
+) -> Result<(), String> {
+    let since = match since {
+        Some(date) => Some(
+            parse_date(date)
+                .filter(|_| date.len() == 10)
+                .ok_or_else(|| format!("invalid date \"{}\" (expected YYYY-MM-DD)", date))?,
+        ),
+        None => None,
+    };
+    let sessions: Vec<LoggedSession> = read_log()?
+        .into_iter()
+        .filter(|s| since.map_or(true, |day| s.day >= day))
+        .collect();
+    if format == StatsFormat::Table {
+        if sessions.is_empty() {
+            println!("No practice recorded yet.");
+            return Ok(());
+        }
+        let groups = match by {
+            Some(by) => vec![by],
+            None => vec![StatsGroup::Day, StatsGroup::Week, StatsGroup::Exercise],
+        };
+        for by in groups {
+            let title = match by {
+                StatsGroup::Day => "Day",
+                StatsGroup::Week => "Week of",
+                StatsGroup::Exercise => "Exercise",
+                StatsGroup::Session => "Session",
+            };
+            println!("{}", table(title, &group(&sessions, by)));
+        }
+        let total: f64 = sessions.iter().map(|s| s.playing_secs).sum();
+        println!(
+            "Total: {} in {} sessions",
+            format_duration(total),
+            sessions.len()
+        );
+        return Ok(());
+    }
+    let by = by.unwrap_or(StatsGroup::Day);
+    let out = match (by, format) {
+        (StatsGroup::Session, StatsFormat::Json) => sessions_json(&sessions),
+        (StatsGroup::Session, _) => sessions_csv(&sessions),
+        (by, StatsFormat::Json) => totals_json(by, &group(&sessions, by)),
+        (by, _) => totals_csv(by, &group(&sessions, by)),
+    };
+    print!("{}", out);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(start: &str, secs: f64, bpm: &str) -> LoggedSession {
+        let line = format!("{},{},{},scales,4/4:{},{}", start, start, secs, secs, bpm);
+        parse_session(&line).unwrap()
+    }
+
+    #[test]
+    fn civil_days_round_trip() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        assert_eq!(days_from_civil(2000, 3, 1), 11_017);
+        assert_eq!(
+            days_from_civil(2024, 2, 29) + 1,
+            days_from_civil(2024, 3, 1)
+        );
+        assert_eq!(
+            days_from_civil(2100, 2, 28) + 1,
+            days_from_civil(2100, 3, 1)
+        );
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        for days in (-800_000..800_000).step_by(97) {
+            let (y, m, d) = civil_from_days(days);
+            assert_eq!(days_from_civil(y, m, d), days);
+        }
+    }
+
+    #[test]
+    fn dates_parse_from_timestamps() {
+        assert_eq!(parse_date("1970-01-01"), Some(0));
+        assert_eq!(
+            parse_date("2024-03-05T19:02:11+01:00"),
+            parse_date("2024-03-05")
+        );
+        assert_eq!(parse_date("2024-02-29"), Some(days_from_civil(2024, 2, 29)));
+        assert_eq!(format_date(parse_date("2024-12-31").unwrap()), "2024-12-31");
+        for bad in [
+            "2024-02-31",
+            "2023-02-29",
+            "2100-02-29",
+            "2024-04-31",
+            "2024-13-01",
+            "2024-00-10",
+            "2024-01-00",
+            "2024-01",
+            "yesterday",
+        ] {
+            assert_eq!(parse_date(bad), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn csv_fields_round_trip() {
+        assert_eq!(split_csv("a,b,,c"), ["a", "b", "", "c"]);
+        assert_eq!(split_csv("\"a,b\",c"), ["a,b", "c"]);
+        assert_eq!(split_csv("\"say \"\"hi\"\"\",x"), ["say \"hi\"", "x"]);
+        assert_eq!(split_csv(""), [""]);
+        let fields = ["plain", "with, comma", "with \"quotes\"", ""];
+        let line: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+        assert_eq!(split_csv(&line.join(",")), fields);
+    }
+
+    #[test]
+    fn sessions_group_by_day_and_monday_week() {
+        let sessions = [
+            session("2024-03-07T20:00:00+01:00", 600.0, "100:600"),
+            session("2024-03-04T08:00:00+01:00", 300.0, "80:300"),
+            session("2024-03-07T09:00:00+01:00", 1200.0, "120:1200"),
+            session("2024-03-11T09:00:00+01:00", 60.0, "90:60"),
+        ];
+
+        let days = group(&sessions, StatsGroup::Day);
+        let keys: Vec<&str> = days.iter().map(|g| g.key.as_str()).collect();
+        assert_eq!(keys, ["2024-03-04", "2024-03-07", "2024-03-11"]);
+        assert_eq!(days[1].sessions, 2);
+        assert_eq!(days[1].secs, 1800.0);
+        let (mean, lo, hi) = days[1].bpm_summary().unwrap();
+        assert!((mean - 340.0 / 3.0).abs() < 1e-9);
+        assert_eq!((lo, hi), (100, 120));
+
+        // 2024-03-04 and 2024-03-11 are Mondays.
+        let weeks = group(&sessions, StatsGroup::Week);
+        let keys: Vec<&str> = weeks.iter().map(|g| g.key.as_str()).collect();
+        assert_eq!(keys, ["2024-03-04", "2024-03-11"]);
+        assert_eq!(weeks[0].sessions, 3);
+        assert_eq!(weeks[0].secs, 2100.0);
+        assert_eq!(weeks[1].sessions, 1);
+    }
+}
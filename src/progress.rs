@@ -0,0 +1,310 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::config::{self, Config};
+use crate::practice::{csv_field, format_date, format_timestamp, parse_date, split_csv};
+
+/// Prefix of config sections that define an exercise, as in
+/// `[exercise.paradiddles]`.
+const SECTION_PREFIX: &str = "exercise.";
+const HEADER: &str = "date,exercise,bpm,source";
+/// Width of the chart when the terminal size is unknown.
+const DEFAULT_WIDTH: u16 = 80;
+
+/// An exercise with a tempo to work up to.
+pub struct Exercise {
+    pub name: String,
+    pub target: u16,
+}
+
+/// Every `[exercise.<name>]` section of the config, in file order.
+pub fn load_exercises(config: &Config) -> Result<Vec<Exercise>, String> {
+    config
+        .sections()
+        .into_iter()
+        .filter_map(|s| s.strip_prefix(SECTION_PREFIX))
+        .map(|name| {
+            let section = format!("{}{}", SECTION_PREFIX, name);
+            let target = config
+                .get_in(&section, "target")
+                .ok_or_else(|| format!("[{}] has no target tempo", section))?;
+            match target.parse::<u16>() {
+                Ok(bpm) if (20..=400).contains(&bpm) => Ok(Exercise {
+                    name: name.to_string(),
+                    target: bpm,
+                }),
+                _ => Err(format!(
+                    "[{}] target must be a tempo from 20 to 400, got \"{}\"",
+                    section, target
+                )),
+            }
+        })
+        .collect()
+}
+
+/// `$XDG_CONFIG_HOME/metronome/progress.csv`, one line per clean tempo.
+pub fn history_path() -> Option<PathBuf> {
+    config::config_dir().map(|dir| dir.join("progress.csv"))
+}
+
+/// Appends today's clean tempo for `exercise`; `source` says where it came
+/// from, `entered` or `train`.
+pub fn record(exercise: &str, bpm: u16, source: &str) -> Result<PathBuf, String> {
+    let path = history_path().ok_or("cannot locate the config directory (HOME is not set)")?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| format!("could not create {}: {}", dir.display(), e))?;
+    }
+    let today = format_timestamp(SystemTime::now());
+    let line = format!(
+        "{},{},{},{}\n",
+        &today[..10],
+        csv_field(exercise),
+        bpm,
+        source
+    );
+    let new = !path.exists();
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .map_err(|e| format!("could not open {}: {}", path.display(), e))?;
+    let text = if new {
+        format!("{}\n{}", HEADER, line)
+    } else {
+        line
+    };
+    file.write_all(text.as_bytes())
+        .map_err(|e| format!("could not write {}: {}", path.display(), e))?;
+    Ok(path)
+}
+
+/// Highest clean tempo of each day for every exercise, by day, as
+/// `(exercise, day, bpm)`.
+fn read_history() -> Result<Vec<(String, i64, u16)>, String> {
+    let Some(path) = history_path() else {
+        return Ok(Vec::new());
+    };
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("could not read {}: {}", path.display(), e)),
+    };
+    Ok(best_per_day(&text, &path))
+}
+
+/// The history in `text`, read from `path`, reduced to the best tempo of
+/// each exercise and day.
+fn best_per_day(text: &str, path: &Path) -> Vec<(String, i64, u16)> {
+    let mut best: Vec<(String, i64, u16)> = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() || line == HEADER {
+            continue;
+        }
+        let fields = split_csv(line);
+        let parsed = match fields.as_slice() {
+            [date, exercise, bpm, _] => parse_date(date)
+                .zip(bpm.parse::<u16>().ok())
+                .map(|(day, bpm)| (exercise.clone(), day, bpm)),
+            _ => None,
+        };
+        let Some((exercise, day, bpm)) = parsed else {
+            eprintln!(
+                "warning: {}: skipping unreadable line {}",
+                path.display(),
+                i + 1
+            );
+            continue;
+        };
+        match best
+            .iter_mut()
+            .find(|(e, d, _)| *e == exercise && *d == day)
+        {
+            Some((_, _, b)) => *b = (*b).max(bpm),
+            None => best.push((exercise, day, bpm)),
+        }
+    }
+    best.sort_by_key(|&(_, day, _)| day);
+    best
+}
+
+/// Best clean tempo of `exercise` so far.
+pub fn best(exercise: &str) -> Option<u16> {
+    read_history()
+        .ok()?
+        .into_iter()
+        .filter(|(e, _, _)| e == exercise)
+        .map(|(_, _, bpm)| bpm)
+        .max()
+}
+
+/// A bar `eighths` eighths of a character cell long.
+fn bar(eighths: usize) -> String {
+    const PARTS: [char; 8] = [' ', '▏', '▎', '▍', '▌', '▋', '▊', '▉'];
+    let mut out = "█".repeat(eighths / 8);
+    if eighths % 8 > 0 {
+        out.push(PARTS[eighths % 8]);
+    }
+    out
+}
+
+fn chart(name: &str, target: Option<u16>, days: &[(i64, u16)], width: u16) -> String {
+    let best = days.iter().map(|&(_, bpm)| bpm).max().unwrap_or(0);
+    let mut out = match target {
+        Some(target) => format!(
+            "{}: best {} BPM of {} ({:.0}%)\n",
+            name,
+            best,
+            target,
+            best as f64 / target as f64 * 100.0
+        ),
+        None => format!("{}: best {} BPM (no target set)\n", name, best),
+    };
+    // Date, two spaces, the bar, then room for the tempo.
+    let cells = (width as usize).saturating_sub(10 + 2 + 5).max(10);
+    let scale = best.max(target.unwrap_or(0)).max(1) as f64;
+    let target_cell = target.map(|t| (t as f64 / scale * cells as f64).round() as usize);
+    for &(day, bpm) in days {
+        let eighths = (bpm as f64 / scale * cells as f64 * 8.0).round() as usize;
+        let mut row = bar(eighths);
+        let len = eighths.div_ceil(8);
+        match target_cell {
+            // Mark the target on rows that fall short of it.
+            Some(at) if len < at => {
+                row.push_str(&" ".repeat(at - 1 - len));
+                row.push('│');
+            }
+            _ => {}
+        }
+        out.push_str(&format!("{}  {} {}\n", format_date(day), row, bpm));
+    }
+    out
+}
+
+/// `metronome progress`: the best clean tempo per day of `name` against
+/// its target, or every exercise's best when no name is given.
+pub fn run_progress(name: Option<&str>, exercises: &[Exercise]) -> Result<(), String> {
+    let history = read_history()?;
+    let target_of = |name: &str| exercises.iter().find(|e| e.name == name).map(|e| e.target);
+    let Some(name) = name else {
+        let mut names: Vec<&str> = exercises.iter().map(|e| e.name.as_str()).collect();
+        for (e, _, _) in &history {
+            if !names.contains(&e.as_str()) {
+                names.push(e);
+            }
+        }
+        if names.is_empty() {
+            println!(
+                "No exercises yet; add an [exercise.NAME] section with a target to the config."
+            );
+        }
+        for name in names {
+            let best = history
+                .iter()
+                .filter(|(e, _, _)| e == name)
+                .map(|&(_, _, bpm)| bpm)
+                .max();
+            let best = best.map_or("--".to_string(), |b| b.to_string());
+            match target_of(name) {
+                Some(target) => println!("{}: best {} of {} BPM", name, best, target),
+                None => println!("{}: best {} BPM", name, best),
+            }
+        }
+        return Ok(());
+    };
+    let days: Vec<(i64, u16)> = history
+        .iter()
+        .filter(|(e, _, _)| e == name)
+        .map(|&(_, day, bpm)| (day, bpm))
+        .collect();
+    if days.is_empty() {
+        return Err(format!(
+            "no tempo recorded for \"{}\" yet; practice it with --exercise \"{}\"",
+            name, name
+        ));
+    }
+    let width = crossterm::terminal::size()
+        .map(|(w, _)| w)
+        .unwrap_or(DEFAULT_WIDTH);
+    print!("{}", chart(name, target_of(name), &days, width));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Chart rows without the title, as (bar, tempo) with the date dropped.
+    fn chart_rows(target: Option<u16>, bpms: &[u16]) -> Vec<(String, String)> {
+        let days: Vec<(i64, u16)> = bpms
+            .iter()
+            .enumerate()
+            .map(|(d, &b)| (d as i64, b))
+            .collect();
+        // 37 columns leave 20 cells for the bars.
+        chart("scales", target, &days, 37)
+            .lines()
+            .skip(1)
+            .map(|line| {
+                let (bar, bpm) = line[12..].rsplit_once(' ').unwrap();
+                (bar.to_string(), bpm.to_string())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bars_are_drawn_in_eighths() {
+        assert_eq!(bar(0), "");
+        assert_eq!(bar(3), "▍");
+        assert_eq!(bar(8), "█");
+        assert_eq!(bar(21), "██▋");
+    }
+
+    #[test]
+    fn target_marker_only_on_rows_below_it() {
+        let rows = chart_rows(Some(100), &[100, 95, 94, 50, 0]);
+        // At the target: a full bar and no marker.
+        assert_eq!(rows[0].0, "█".repeat(20));
+        // Rows short of the target end with the marker in the target's cell.
+        assert_eq!(rows[1].0, format!("{}│", "█".repeat(19)));
+        assert_eq!(rows[2].0, format!("{}▊│", "█".repeat(18)));
+        assert_eq!(rows[3].0, format!("{}{}│", "█".repeat(10), " ".repeat(9)));
+        assert_eq!(rows[4].0, format!("{}│", " ".repeat(19)));
+        assert_eq!(rows[4].1, "0");
+    }
+
+    #[test]
+    fn rows_past_the_target_have_no_marker() {
+        // The scale follows the best tempo; the target falls on cell 17.
+        let rows = chart_rows(Some(100), &[120, 110, 102, 96]);
+        assert_eq!(rows[0].0, "█".repeat(20));
+        assert_eq!(rows[1].0, format!("{}▍", "█".repeat(18)));
+        assert_eq!(rows[2].0, "█".repeat(17));
+        assert_eq!(rows[3].0, format!("{}│", "█".repeat(16)));
+        assert!(chart("scales", Some(100), &[(0, 120)], 37).contains("best 120 BPM of 100 (120%)"));
+        let rows = chart_rows(None, &[50]);
+        assert_eq!(rows[0].0, "█".repeat(20));
+    }
+
+    #[test]
+    fn history_keeps_the_best_tempo_per_day() {
+        let text = format!(
+            "{}\n2024-03-04,scales,90,entered\n2024-03-05,scales,100,train\n\
+             2024-03-04,scales,96,train\n2024-03-04,\"arps, fast\",70,train\n\
+             not a line\n2024-03-04,scales,80,entered\n",
+            HEADER
+        );
+        let best = best_per_day(&text, Path::new("progress.csv"));
+        let day = parse_date("2024-03-04").unwrap();
+        assert_eq!(
+            best,
+            [
+                ("scales".to_string(), day, 96),
+                ("arps, fast".to_string(), day, 70),
+                ("scales".to_string(), day + 1, 100),
+            ]
+        );
+    }
+}
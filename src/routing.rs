@@ -0,0 +1,244 @@
+use crate::config::Config;
+
+/// A part of the click pattern that can be panned and routed on its own.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Layer {
+    /// The downbeat of each bar.
+    Accent,
+    /// The other beats.
+    Beat,
+    /// Ticks between beats.
+    Sub,
+}
+
+impl Layer {
+    pub const ALL: [Layer; 3] = [Layer::Accent, Layer::Beat, Layer::Sub];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Layer::Accent => "accent",
+            Layer::Beat => "beat",
+            Layer::Sub => "sub",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<Layer, String> {
+        Layer::ALL
+            .into_iter()
+            .find(|l| l.name() == name.trim())
+            .ok_or_else(|| format!("unknown click layer \"{}\" (accent, beat, sub)", name))
+    }
+}
+
+/// Where one layer is heard.
+#[derive(Clone, Debug)]
+pub struct Route {
+    /// Balance across a pair of channels: -1.0 is left only, 1.0 right only.
+    pub pan: f32,
+    /// Output channels, counted from 1.
+    pub channels: Vec<u16>,
+}
+
+impl Default for Route {
+    fn default() -> Self {
+        Route {
+            pan: 0.0,
+            channels: vec![1, 2],
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Routing {
+    routes: [Route; 3],
+}
+
+/// `left`, `center`, `right` or a number from -1 to 1.
+pub fn parse_pan(s: &str) -> Result<f32, String> {
+    let pan = match s.trim().to_lowercase().as_str() {
+        "left" | "l" => -1.0,
+        "center" | "centre" | "c" => 0.0,
+        "right" | "r" => 1.0,
+        other => other
+            .parse::<f32>()
+            .map_err(|_| format!("invalid pan \"{}\"", s))?,
+    };
+    if !(-1.0..=1.0).contains(&pan) {
+        return Err(format!("pan must be between -1 and 1, got {}", s));
+    }
+    Ok(pan)
+}
+
+/// A channel list such as `3`, `3,4` or `3-4`.
+pub fn parse_channels(s: &str) -> Result<Vec<u16>, String> {
+    let invalid = || format!("invalid channel list \"{}\"", s);
+    let mut channels = Vec::new();
+    for part in s.split(',') {
+        let (first, last) = match part.split_once('-') {
+            Some((a, b)) => (a.trim(), b.trim()),
+            None => (part.trim(), part.trim()),
+        };
+        let first: u16 = first.parse().map_err(|_| invalid())?;
+        let last: u16 = last.parse().map_err(|_| invalid())?;
+        if first == 0 || last < first || last > 64 {
+            return Err(invalid());
+        }
+        channels.extend(first..=last);
+    }
+    Ok(channels)
+}
+
+/// Splits `layer=value` into the layers it applies to and the value; with
+/// no layer it applies to all of them.
+pub fn split_layer(arg: &str) -> Result<(Vec<Layer>, &str), String> {
+    match arg.split_once('=') {
+        Some((layer, value)) => Ok((vec![Layer::parse(layer)?], value)),
+        None => Ok((Layer::ALL.to_vec(), arg)),
+    }
+}
+
+impl Routing {
+    /// Reads `[accent]`, `[beat]` and `[sub]` config sections, then applies
+    /// `--pan` and `--channels` arguments on top.
+    pub fn from_settings(
+        config: &Config,
+        pans: &[String],
+        channels: &[String],
+    ) -> Result<Routing, String> {
+        let mut routing = Routing::default();
+        for layer in Layer::ALL {
+            let route = routing.route_mut(layer);
+            if let Some(pan) = config.get_in(layer.name(), "pan") {
+                route.pan = parse_pan(pan).map_err(|e| format!("[{}] {}", layer.name(), e))?;
+            }
+            if let Some(list) = config.get_in(layer.name(), "channels") {
+                route.channels =
+                    parse_channels(list).map_err(|e| format!("[{}] {}", layer.name(), e))?;
+            }
+        }
+        for arg in pans {
+            let (layers, value) = split_layer(arg)?;
+            let pan = parse_pan(value)?;
+            for layer in layers {
+                routing.route_mut(layer).pan = pan;
+            }
+        }
+        for arg in channels {
+            let (layers, value) = split_layer(arg)?;
+            let list = parse_channels(value)?;
+            for layer in layers {
+                routing.route_mut(layer).channels = list.clone();
+            }
+        }
+        Ok(routing)
+    }
+
+    pub fn route(&self, layer: Layer) -> &Route {
+        &self.routes[layer as usize]
+    }
+
+    fn route_mut(&mut self, layer: Layer) -> &mut Route {
+        &mut self.routes[layer as usize]
+    }
+
+    /// Channels the mix needs: stereo, or more if a layer is routed beyond.
+    pub fn channels(&self) -> u16 {
+        self.routes
+            .iter()
+            .flat_map(|r| r.channels.iter().copied())
+            .max()
+            .unwrap_or(2)
+            .max(2)
+    }
+
+    /// Gain of `layer` on each channel of a mix `channels` wide. The pan
+    /// applies when a layer goes to exactly two channels.
+    pub fn gains(&self, layer: Layer, channels: u16) -> Vec<f32> {
+        let route = self.route(layer);
+        let mut gains = vec![0.0; channels as usize];
+        let mut set = |channel: u16, gain: f32| {
+            if let Some(g) = gains.get_mut(channel as usize - 1) {
+                *g = gain;
+            }
+        };
+        match route.channels.as_slice() {
+            &[left, right] => {
+                set(left, (1.0 - route.pan).min(1.0));
+                set(right, (1.0 + route.pan).min(1.0));
+            }
+            list => list.iter().for_each(|&c| set(c, 1.0)),
+        }
+        gains
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config;
+
+    fn routing(config: &str, pans: &[&str], channels: &[&str]) -> Result<Routing, String> {
+        let strings = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        let config = parse_config(config).unwrap();
+        Routing::from_settings(&config, &strings(pans), &strings(channels))
+    }
+
+    #[test]
+    fn pans_by_name_or_number() {
+        assert_eq!(parse_pan("left"), Ok(-1.0));
+        assert_eq!(parse_pan(" R "), Ok(1.0));
+        assert_eq!(parse_pan("centre"), Ok(0.0));
+        assert_eq!(parse_pan("-0.25"), Ok(-0.25));
+        assert!(parse_pan("1.5").is_err());
+        assert!(parse_pan("up").is_err());
+    }
+
+    #[test]
+    fn channel_lists_and_ranges() {
+        assert_eq!(parse_channels("3"), Ok(vec![3]));
+        assert_eq!(parse_channels("3, 4"), Ok(vec![3, 4]));
+        assert_eq!(parse_channels("1,3-5"), Ok(vec![1, 3, 4, 5]));
+        for bad in ["0", "4-3", "65", "1-65", "x", "", "3,"] {
+            assert!(parse_channels(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn pan_turns_down_the_far_channel_only() {
+        let routing = routing("", &["accent=left", "beat=-0.5"], &[]).unwrap();
+        assert_eq!(routing.gains(Layer::Accent, 2), [1.0, 0.0]);
+        assert_eq!(routing.gains(Layer::Beat, 2), [1.0, 0.5]);
+        assert_eq!(routing.gains(Layer::Sub, 2), [1.0, 1.0]);
+    }
+
+    #[test]
+    fn layers_play_on_their_own_channels() {
+        let routing = routing("", &["right"], &["sub=3", "accent=3-5"]).unwrap();
+        assert_eq!(routing.channels(), 5);
+        assert_eq!(routing.gains(Layer::Sub, 5), [0.0, 0.0, 1.0, 0.0, 0.0]);
+        // Pan only applies to a pair of channels.
+        assert_eq!(routing.gains(Layer::Accent, 5), [0.0, 0.0, 1.0, 1.0, 1.0]);
+        assert_eq!(routing.gains(Layer::Beat, 5), [0.0, 1.0, 0.0, 0.0, 0.0]);
+        // Channels beyond a narrower mix are dropped.
+        assert_eq!(routing.gains(Layer::Accent, 4), [0.0, 0.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn arguments_override_the_config() {
+        let config = "[beat]\npan = left\nchannels = 3-4\n\n[sub]\npan = 0.5\n";
+        let routing = routing(config, &["beat=right"], &[]).unwrap();
+        assert_eq!(routing.route(Layer::Beat).pan, 1.0);
+        assert_eq!(routing.route(Layer::Beat).channels, [3, 4]);
+        assert_eq!(routing.route(Layer::Sub).pan, 0.5);
+        assert_eq!(routing.route(Layer::Accent).channels, [1, 2]);
+        assert_eq!(routing.channels(), 4);
+    }
+
+    #[test]
+    fn errors_name_the_layer_or_section() {
+        let err = routing("[sub]\npan = 2\n", &[], &[]).err().unwrap();
+        assert!(err.starts_with("[sub] "), "{}", err);
+        let err = routing("", &["bass=left"], &[]).err().unwrap();
+        assert!(err.contains("unknown click layer \"bass\""), "{}", err);
+    }
+}
@@ -0,0 +1,772 @@
+use std::path::Path;
+
+use crate::routing::{Layer, split_layer};
+
+// Generator operators used when rendering.
+const START_OFFSET: u16 = 0;
+const END_OFFSET: u16 = 1;
+const STARTLOOP_OFFSET: u16 = 2;
+const ENDLOOP_OFFSET: u16 = 3;
+const START_COARSE_OFFSET: u16 = 4;
+const END_COARSE_OFFSET: u16 = 12;
+const DELAY_VOL_ENV: u16 = 33;
+const ATTACK_VOL_ENV: u16 = 34;
+const HOLD_VOL_ENV: u16 = 35;
+const DECAY_VOL_ENV: u16 = 36;
+const SUSTAIN_VOL_ENV: u16 = 37;
+const RELEASE_VOL_ENV: u16 = 38;
+const INSTRUMENT: u16 = 41;
+const KEY_RANGE: u16 = 43;
+const VEL_RANGE: u16 = 44;
+const STARTLOOP_COARSE_OFFSET: u16 = 45;
+const INITIAL_ATTENUATION: u16 = 48;
+const ENDLOOP_COARSE_OFFSET: u16 = 50;
+const COARSE_TUNE: u16 = 51;
+const FINE_TUNE: u16 = 52;
+const SAMPLE_ID: u16 = 53;
+const SAMPLE_MODES: u16 = 54;
+const SCALE_TUNING: u16 = 56;
+const OVERRIDING_ROOT_KEY: u16 = 58;
+
+/// Generators a preset zone adds to its instrument's value.
+const ADDITIVE: [u16; 10] = [
+    DELAY_VOL_ENV,
+    ATTACK_VOL_ENV,
+    HOLD_VOL_ENV,
+    DECAY_VOL_ENV,
+    SUSTAIN_VOL_ENV,
+    RELEASE_VOL_ENV,
+    INITIAL_ATTENUATION,
+    COARSE_TUNE,
+    FINE_TUNE,
+    SCALE_TUNING,
+];
+
+/// How long a click note is held before its release starts.
+const GATE_SECS: f64 = 0.3;
+/// Longest rendered click, release included.
+const MAX_SECS: f64 = 2.0;
+/// Level of a full-scale sample relative to the built-in clicks.
+const OUTPUT_GAIN: f32 = 0.5;
+
+/// A generator list: a preset or instrument zone.
+#[derive(Clone, Default)]
+struct Zone {
+    gens: Vec<(u16, u16)>,
+}
+
+impl Zone {
+    fn get(&self, op: u16) -> Option<u16> {
+        self.gens
+            .iter()
+            .rev()
+            .find(|(o, _)| *o == op)
+            .map(|(_, v)| *v)
+    }
+
+    fn range(&self, op: u16) -> Option<(u8, u8)> {
+        self.get(op).map(|v| ((v & 0xff) as u8, (v >> 8) as u8))
+    }
+
+    fn matches(&self, global: &Zone, key: u8, vel: u8) -> bool {
+        let within = |op| {
+            let (lo, hi) = self.range(op).or(global.range(op)).unwrap_or((0, 127));
+            move |v: u8| lo <= v && v <= hi
+        };
+        within(KEY_RANGE)(key) && within(VEL_RANGE)(vel)
+    }
+}
+
+/// Zones of a preset or instrument, the global zone kept apart.
+struct Zones {
+    global: Zone,
+    zones: Vec<Zone>,
+}
+
+struct Preset {
+    name: String,
+    bank: u16,
+    program: u16,
+    zones: Zones,
+}
+
+struct SampleHeader {
+    start: u32,
+    end: u32,
+    loop_start: u32,
+    loop_end: u32,
+    sample_rate: u32,
+    root_key: u8,
+    correction: i8,
+}
+
+/// A parsed SF2 file.
+pub struct SoundFont {
+    samples: Vec<i16>,
+    presets: Vec<Preset>,
+    instruments: Vec<Zones>,
+    headers: Vec<SampleHeader>,
+}
+
+/// Which note of the soundfont a click layer plays.
+#[derive(Copy, Clone, Debug)]
+pub struct NoteSpec {
+    pub bank: u16,
+    pub program: u16,
+    pub key: u8,
+    pub velocity: u8,
+}
+
+/// A soundfont with the note each click layer plays.
+pub struct SoundFontVoices {
+    font: SoundFont,
+    notes: [NoteSpec; 3],
+}
+
+fn u16_at(data: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([data[at], data[at + 1]])
+}
+
+fn u32_at(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+fn name_at(data: &[u8], at: usize) -> String {
+    let raw = &data[at..at + 20];
+    let len = raw.iter().position(|&b| b == 0).unwrap_or(raw.len());
+    String::from_utf8_lossy(&raw[..len]).trim().to_string()
+}
+
+/// A RIFF chunk as (id, data).
+type Chunk<'a> = ([u8; 4], &'a [u8]);
+
+/// Sub-chunks of a RIFF list body.
+fn chunks(data: &[u8]) -> Result<Vec<Chunk<'_>>, String> {
+    let mut out = Vec::new();
+    let mut at = 0;
+    while at + 8 <= data.len() {
+        let id = [data[at], data[at + 1], data[at + 2], data[at + 3]];
+        let len = u32_at(data, at + 4) as usize;
+        let body = data
+            .get(at + 8..at + 8 + len)
+            .ok_or_else(|| format!("chunk {} runs past the end of the file", ascii(&id)))?;
+        out.push((id, body));
+        at += 8 + len + len % 2;
+    }
+    Ok(out)
+}
+
+fn ascii(id: &[u8; 4]) -> String {
+    String::from_utf8_lossy(id).to_string()
+}
+
+/// Records of `size` bytes in a pdta sub-chunk.
+fn records<'a>(pdta: &[Chunk<'a>], id: &[u8; 4], size: usize) -> Result<Vec<&'a [u8]>, String> {
+    let (_, data) = pdta
+        .iter()
+        .find(|(i, _)| i == id)
+        .ok_or_else(|| format!("missing {} chunk", ascii(id)))?;
+    if data.len() % size != 0 {
+        return Err(format!("malformed {} chunk", ascii(id)));
+    }
+    Ok(data.chunks(size).collect())
+}
+
+/// Zones of each record in a phdr/inst style list; `bag_at` is the offset
+/// of the bag index in a record and `terminal` the generator that ends a
+/// non-global zone.
+fn build_zones(
+    headers: &[&[u8]],
+    bag_at: usize,
+    bags: &[&[u8]],
+    gens: &[&[u8]],
+    terminal: u16,
+) -> Result<Vec<Zones>, String> {
+    let bag_index = |h: &[u8]| u16_at(h, bag_at) as usize;
+    let mut out = Vec::new();
+    for pair in headers.windows(2) {
+        let (first, last) = (bag_index(pair[0]), bag_index(pair[1]));
+        if first > last || last >= bags.len() {
+            return Err("malformed zone index".to_string());
+        }
+        let mut zones = Zones {
+            global: Zone::default(),
+            zones: Vec::new(),
+        };
+        for (n, b) in (first..last).enumerate() {
+            let (g0, g1) = (u16_at(bags[b], 0) as usize, u16_at(bags[b + 1], 0) as usize);
+            if g0 > g1 || g1 > gens.len() {
+                return Err("malformed generator index".to_string());
+            }
+            let zone = Zone {
+                gens: gens[g0..g1]
+                    .iter()
+                    .map(|g| (u16_at(g, 0), u16_at(g, 2)))
+                    .collect(),
+            };
+            match zone.gens.last() {
+                Some((op, _)) if *op == terminal => zones.zones.push(zone),
+                // Only the first zone may be global; later ones are ignored.
+                _ if n == 0 => zones.global = zone,
+                _ => {}
+            }
+        }
+        out.push(zones);
+    }
+    Ok(out)
+}
+
+/// 2^(timecents/1200) seconds.
+fn timecents(tc: i32) -> f64 {
+    2f64.powf(tc.max(-12000) as f64 / 1200.0)
+}
+
+impl SoundFont {
+    pub fn load(path: &Path) -> Result<SoundFont, String> {
+        let data =
+            std::fs::read(path).map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+        SoundFont::parse(&data).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    fn parse(data: &[u8]) -> Result<SoundFont, String> {
+        if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"sfbk" {
+            return Err("not an SF2 file".to_string());
+        }
+        let mut sdta = None;
+        let mut pdta = None;
+        for (id, body) in chunks(&data[12..])? {
+            if &id == b"LIST" && body.len() >= 4 {
+                match &body[0..4] {
+                    b"sdta" => sdta = Some(chunks(&body[4..])?),
+                    b"pdta" => pdta = Some(chunks(&body[4..])?),
+                    _ => {}
+                }
+            }
+        }
+        let sdta = sdta.ok_or("missing sample data")?;
+        let pdta = pdta.ok_or("missing preset data")?;
+        let samples: Vec<i16> = sdta
+            .iter()
+            .find(|(id, _)| id == b"smpl")
+            .map(|(_, d)| {
+                d.chunks_exact(2)
+                    .map(|b| i16::from_le_bytes([b[0], b[1]]))
+                    .collect()
+            })
+            .ok_or("missing smpl chunk")?;
+        if samples.is_empty() {
+            return Err("empty smpl chunk".to_string());
+        }
+
+        let phdr = records(&pdta, b"phdr", 38)?;
+        let pbag = records(&pdta, b"pbag", 4)?;
+        let pgen = records(&pdta, b"pgen", 4)?;
+        let inst = records(&pdta, b"inst", 22)?;
+        let ibag = records(&pdta, b"ibag", 4)?;
+        let igen = records(&pdta, b"igen", 4)?;
+        let shdr = records(&pdta, b"shdr", 46)?;
+
+        let preset_zones = build_zones(&phdr, 24, &pbag, &pgen, INSTRUMENT)?;
+        let presets = phdr
+            .iter()
+            .zip(preset_zones)
+            .map(|(h, zones)| Preset {
+                name: name_at(h, 0),
+                program: u16_at(h, 20),
+                bank: u16_at(h, 22),
+                zones,
+            })
+            .collect();
+        let instruments = build_zones(&inst, 20, &ibag, &igen, SAMPLE_ID)?;
+        let headers = shdr
+            .iter()
+            .map(|h| SampleHeader {
+                start: u32_at(h, 20),
+                end: u32_at(h, 24),
+                loop_start: u32_at(h, 28),
+                loop_end: u32_at(h, 32),
+                sample_rate: u32_at(h, 36).max(1),
+                root_key: h[40],
+                correction: h[41] as i8,
+            })
+            .collect();
+        Ok(SoundFont {
+            samples,
+            presets,
+            instruments,
+            headers,
+        })
+    }
+
+    fn preset(&self, bank: u16, program: u16) -> Option<&Preset> {
+        self.presets
+            .iter()
+            .find(|p| p.bank == bank && p.program == program)
+    }
+
+    /// (bank, program) of the GM drum kit if there is one, else the first
+    /// preset.
+    fn default_preset(&self) -> Option<(u16, u16)> {
+        self.preset(128, 0)
+            .or(self.presets.first())
+            .map(|p| (p.bank, p.program))
+    }
+
+    /// Generator sets of every sample the note plays, each holding final
+    /// instrument values with the preset's offsets applied.
+    fn regions(&self, note: &NoteSpec) -> Vec<(usize, Zone)> {
+        let Some(preset) = self.preset(note.bank, note.program) else {
+            return Vec::new();
+        };
+        let mut regions = Vec::new();
+        let pglobal = &preset.zones.global;
+        for pzone in &preset.zones.zones {
+            if !pzone.matches(pglobal, note.key, note.velocity) {
+                continue;
+            }
+            let Some(inst) = pzone
+                .get(INSTRUMENT)
+                .and_then(|i| self.instruments.get(i as usize))
+            else {
+                continue;
+            };
+            for izone in &inst.zones {
+                if !izone.matches(&inst.global, note.key, note.velocity) {
+                    continue;
+                }
+                let Some(sample) = izone.get(SAMPLE_ID).map(|s| s as usize) else {
+                    continue;
+                };
+                if sample >= self.headers.len() {
+                    continue;
+                }
+                let mut merged = inst.global.clone();
+                merged.gens.extend(izone.gens.iter().copied());
+                for op in ADDITIVE {
+                    if let Some(add) = pzone.get(op).or(pglobal.get(op)) {
+                        let base = merged
+                            .get(op)
+                            .map(|v| v as i16 as i32)
+                            .unwrap_or(default(op));
+                        merged
+                            .gens
+                            .push((op, (base + add as i16 as i32) as i16 as u16));
+                    }
+                }
+                regions.push((sample, merged));
+            }
+        }
+        regions
+    }
+
+    /// Renders one region of a note as mono samples at `out_rate`.
+    fn render_region(
+        &self,
+        sample: usize,
+        zone: &Zone,
+        note: &NoteSpec,
+        out_rate: u32,
+    ) -> Vec<f32> {
+        let h = &self.headers[sample];
+        let gen = |op| zone.get(op).map(|v| v as i16 as i32).unwrap_or(default(op));
+        let offset = |base: u32, fine, coarse| {
+            (base as i64 + gen(fine) as i64 + 32768 * gen(coarse) as i64)
+                .clamp(0, self.samples.len() as i64 - 1) as f64
+        };
+        let start = offset(h.start, START_OFFSET, START_COARSE_OFFSET);
+        let end = offset(h.end, END_OFFSET, END_COARSE_OFFSET);
+        let loop_start = offset(h.loop_start, STARTLOOP_OFFSET, STARTLOOP_COARSE_OFFSET);
+        let loop_end = offset(h.loop_end, ENDLOOP_OFFSET, ENDLOOP_COARSE_OFFSET);
+        let mode = gen(SAMPLE_MODES) & 3;
+        let can_loop = (mode == 1 || mode == 3) && loop_end > loop_start + 1.0;
+
+        let root = match gen(OVERRIDING_ROOT_KEY) {
+            k @ 0..=127 => k,
+            _ if h.root_key <= 127 => h.root_key as i32,
+            _ => 60,
+        };
+        let semitones = (note.key as i32 - root) as f64 * gen(SCALE_TUNING) as f64 / 100.0
+            + gen(COARSE_TUNE) as f64
+            + (gen(FINE_TUNE) + h.correction as i32) as f64 / 100.0;
+        let step = 2f64.powf(semitones / 12.0) * h.sample_rate as f64 / out_rate as f64;
+
+        let delay = timecents(gen(DELAY_VOL_ENV));
+        let attack = timecents(gen(ATTACK_VOL_ENV));
+        let hold = timecents(gen(HOLD_VOL_ENV));
+        let decay = timecents(gen(DECAY_VOL_ENV));
+        let sustain_db = gen(SUSTAIN_VOL_ENV).clamp(0, 1440) as f64 / 10.0;
+        let release = timecents(gen(RELEASE_VOL_ENV));
+        // Level before note-off: attack is linear in amplitude, decay falls
+        // 100 dB over `decay` seconds down to the sustain level.
+        let held = |t: f64| -> f64 {
+            let t = t - delay;
+            if t < 0.0 {
+                0.0
+            } else if t < attack {
+                t / attack
+            } else if t < attack + hold {
+                1.0
+            } else {
+                let db = (100.0 * (t - attack - hold) / decay).min(sustain_db);
+                10f64.powf(-db / 20.0)
+            }
+        };
+        let velocity_db = 40.0 * (127.0 / note.velocity.max(1) as f64).log10();
+        let gain = 10f64.powf(-(gen(INITIAL_ATTENUATION).max(0) as f64 / 10.0 + velocity_db) / 20.0)
+            as f32
+            * OUTPUT_GAIN;
+
+        let release_level = held(GATE_SECS);
+        let mut out = Vec::new();
+        let mut pos = start;
+        let max_len = (MAX_SECS * out_rate as f64) as usize;
+        while out.len() < max_len {
+            let t = out.len() as f64 / out_rate as f64;
+            let released = t >= GATE_SECS;
+            let level = if released {
+                let db = 100.0 * (t - GATE_SECS) / release;
+                if db >= 100.0 {
+                    break;
+                }
+                release_level * 10f64.powf(-db / 20.0)
+            } else {
+                held(t)
+            };
+            let looping = can_loop && (mode == 1 || !released);
+            if looping && pos >= loop_end {
+                // A high note can step over more than one loop.
+                pos = loop_start + (pos - loop_start).rem_euclid(loop_end - loop_start);
+            }
+            if !looping && pos >= end - 1.0 {
+                break;
+            }
+            let i = pos as usize;
+            let frac = (pos - i as f64) as f32;
+            let Some(&a) = self.samples.get(i) else {
+                break;
+            };
+            let a = a as f32 / 32768.0;
+            let b = self.samples.get(i + 1).copied().unwrap_or(0) as f32 / 32768.0;
+            out.push((a + (b - a) * frac) * level as f32 * gain);
+            pos += step;
+        }
+        out
+    }
+
+    /// Renders a note, mixing every sample it layers.
+    fn render(&self, note: &NoteSpec, out_rate: u32) -> Vec<f32> {
+        let mut out: Vec<f32> = Vec::new();
+        for (sample, zone) in self.regions(note) {
+            let part = self.render_region(sample, &zone, note, out_rate);
+            if part.len() > out.len() {
+                out.resize(part.len(), 0.0);
+            }
+            out.iter_mut().zip(&part).for_each(|(o, p)| *o += p);
+        }
+        out
+    }
+}
+
+/// Default value of a generator at instrument level.
+fn default(op: u16) -> i32 {
+    match op {
+        DELAY_VOL_ENV | ATTACK_VOL_ENV | HOLD_VOL_ENV | DECAY_VOL_ENV | RELEASE_VOL_ENV => -12000,
+        SCALE_TUNING => 100,
+        OVERRIDING_ROOT_KEY => -1,
+        _ => 0,
+    }
+}
+
+/// `[BANK:]PROGRAM:KEY[@VELOCITY]`, e.g. `128:0:75` or `0:115@100`.
+fn parse_note(s: &str, fallback: NoteSpec) -> Result<NoteSpec, String> {
+    let invalid = || {
+        format!(
+            "invalid soundfont note \"{}\" (expected [BANK:]PROGRAM:KEY[@VELOCITY])",
+            s
+        )
+    };
+    let (spec, velocity) = match s.split_once('@') {
+        Some((spec, vel)) => (spec, vel.trim().parse::<u8>().map_err(|_| invalid())?),
+        None => (s, fallback.velocity),
+    };
+    let parts = spec
+        .split(':')
+        .map(|p| p.trim().parse::<u16>().map_err(|_| invalid()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let (bank, program, key) = match parts[..] {
+        [key] => (fallback.bank, fallback.program, key),
+        [program, key] => (fallback.bank, program, key),
+        [bank, program, key] => (bank, program, key),
+        _ => return Err(invalid()),
+    };
+    if key > 127 || !(1..=127).contains(&velocity) {
+        return Err(invalid());
+    }
+    Ok(NoteSpec {
+        bank,
+        program,
+        key: key as u8,
+        velocity,
+    })
+}
+
+impl SoundFontVoices {
+    /// Loads `path` and picks each layer's note from `--sf2-note` arguments
+    /// (`[LAYER=]NOTE`), by default wood blocks and claves from the GM kit.
+    pub fn load(path: &Path, args: &[String]) -> Result<SoundFontVoices, String> {
+        let font = SoundFont::load(path)?;
+        let (bank, program) = font
+            .default_preset()
+            .ok_or_else(|| format!("{} has no presets", path.display()))?;
+        let note = |key, velocity| NoteSpec {
+            bank,
+            program,
+            key,
+            velocity,
+        };
+        // GM percussion: high wood block, low wood block, claves.
+        let mut notes = [note(76, 127), note(77, 100), note(75, 80)];
+        for arg in args {
+            let (layers, value) = split_layer(arg)?;
+            for layer in layers {
+                notes[layer as usize] = parse_note(value, notes[layer as usize])?;
+            }
+        }
+        for layer in Layer::ALL {
+            let n = notes[layer as usize];
+            let preset = font.preset(n.bank, n.program).ok_or_else(|| {
+                format!(
+                    "{} has no preset {}:{} (for {})",
+                    path.display(),
+                    n.bank,
+                    n.program,
+                    layer.name()
+                )
+            })?;
+            if font.regions(&n).is_empty() {
+                return Err(format!(
+                    "preset {}:{} \"{}\" has no sound on key {} (for {})",
+                    n.bank,
+                    n.program,
+                    preset.name,
+                    n.key,
+                    layer.name()
+                ));
+            }
+        }
+        Ok(SoundFontVoices { font, notes })
+    }
+
+    pub fn render(&self, layer: Layer, sample_rate: u32) -> Vec<f32> {
+        self.font.render(&self.notes[layer as usize], sample_rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 8000;
+
+    fn chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut out = id.to_vec();
+        out.extend((body.len() as u32).to_le_bytes());
+        out.extend(body);
+        if body.len() % 2 == 1 {
+            out.push(0);
+        }
+        out
+    }
+
+    fn list(kind: &[u8; 4], parts: &[Vec<u8>]) -> Vec<u8> {
+        let mut body = kind.to_vec();
+        parts.iter().for_each(|p| body.extend(p));
+        chunk(b"LIST", &body)
+    }
+
+    fn record(size: usize, fields: &[(usize, &[u8])]) -> Vec<u8> {
+        let mut out = vec![0; size];
+        for (at, bytes) in fields {
+            out[*at..*at + bytes.len()].copy_from_slice(bytes);
+        }
+        out
+    }
+
+    fn gens(list: &[(u16, u16)]) -> Vec<u8> {
+        list.iter()
+            .flat_map(|(op, v)| [op.to_le_bytes(), v.to_le_bytes()].concat())
+            .collect()
+    }
+
+    /// One drum kit preset (128:0) playing one sample with the given loop
+    /// and sample mode, recorded at `RATE` with root key 60.
+    fn font(samples: &[i16], loop_range: (u32, u32), mode: u16) -> Vec<u8> {
+        let smpl: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+        let phdr = [
+            record(38, &[(0, b"Kit"), (22, &128u16.to_le_bytes())]),
+            record(38, &[(0, b"EOP"), (24, &1u16.to_le_bytes())]),
+        ]
+        .concat();
+        let inst = [
+            record(22, &[(0, b"Block")]),
+            record(22, &[(0, b"EOI"), (20, &1u16.to_le_bytes())]),
+        ]
+        .concat();
+        let bags = |n: u16| {
+            [0u16, 0, n, 0]
+                .iter()
+                .flat_map(|v| v.to_le_bytes())
+                .collect::<Vec<u8>>()
+        };
+        let shdr = [
+            record(
+                46,
+                &[
+                    (20, &0u32.to_le_bytes()),
+                    (24, &(samples.len() as u32).to_le_bytes()),
+                    (28, &loop_range.0.to_le_bytes()),
+                    (32, &loop_range.1.to_le_bytes()),
+                    (36, &RATE.to_le_bytes()),
+                    (40, &[60]),
+                ],
+            ),
+            record(46, &[(0, b"EOS")]),
+        ]
+        .concat();
+        let body = [
+            b"sfbk".to_vec(),
+            list(b"INFO", &[chunk(b"ifil", &[2, 0, 1, 0])]),
+            list(b"sdta", &[chunk(b"smpl", &smpl)]),
+            list(
+                b"pdta",
+                &[
+                    chunk(b"phdr", &phdr),
+                    chunk(b"pbag", &bags(1)),
+                    chunk(b"pmod", &[0; 10]),
+                    chunk(b"pgen", &gens(&[(INSTRUMENT, 0), (0, 0)])),
+                    chunk(b"inst", &inst),
+                    chunk(b"ibag", &bags(2)),
+                    chunk(b"imod", &[0; 10]),
+                    chunk(
+                        b"igen",
+                        &gens(&[(SAMPLE_MODES, mode), (SAMPLE_ID, 0), (0, 0)]),
+                    ),
+                    chunk(b"shdr", &shdr),
+                ],
+            ),
+        ]
+        .concat();
+        chunk(b"RIFF", &body)
+    }
+
+    fn drum(key: u8) -> NoteSpec {
+        NoteSpec {
+            bank: 128,
+            program: 0,
+            key,
+            velocity: 127,
+        }
+    }
+
+    #[test]
+    fn parses_a_minimal_font() {
+        let sf = SoundFont::parse(&font(&[1000; 100], (20, 40), 1)).unwrap();
+        assert_eq!(sf.samples.len(), 100);
+        assert_eq!(sf.default_preset(), Some((128, 0)));
+        assert_eq!(sf.preset(128, 0).unwrap().name, "Kit");
+        assert_eq!(sf.regions(&drum(76)).len(), 1);
+        assert!(sf.regions(&drum(76)).iter().all(|(s, _)| *s == 0));
+    }
+
+    #[test]
+    fn truncated_files_are_errors() {
+        let data = font(&[1000; 100], (20, 40), 1);
+        for len in 0..data.len() {
+            assert!(
+                SoundFont::parse(&data[..len]).is_err(),
+                "truncated at {}",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_chunks_are_errors() {
+        let data = font(&[1000; 100], (20, 40), 1);
+        let at = |id: &[u8]| data.windows(id.len()).position(|w| w == id).unwrap();
+
+        // A phdr whose size is not a whole number of records.
+        let mut short = data.clone();
+        let phdr = at(b"phdr");
+        short[phdr + 4..phdr + 8].copy_from_slice(&37u32.to_le_bytes());
+        assert!(SoundFont::parse(&short).is_err());
+
+        // A preset whose zones point past the bag list.
+        let mut zones = data.clone();
+        let eop = at(b"EOP");
+        zones[eop + 24..eop + 26].copy_from_slice(&9u16.to_le_bytes());
+        assert!(SoundFont::parse(&zones).is_err());
+
+        // A bag whose generators point past the generator list.
+        let mut bag = data.clone();
+        let ibag = at(b"ibag") + 8;
+        bag[ibag + 4..ibag + 6].copy_from_slice(&9u16.to_le_bytes());
+        assert!(SoundFont::parse(&bag).is_err());
+
+        // A missing chunk.
+        let mut missing = data.clone();
+        let shdr = at(b"shdr");
+        missing[shdr..shdr + 4].copy_from_slice(b"xxxx");
+        assert!(SoundFont::parse(&missing).is_err());
+
+        assert!(SoundFont::parse(b"RIFF\0\0\0\0WAVE").is_err());
+    }
+
+    #[test]
+    fn empty_sample_data_is_an_error() {
+        let err = SoundFont::parse(&font(&[], (0, 0), 0)).err().unwrap();
+        assert_eq!(err, "empty smpl chunk");
+    }
+
+    #[test]
+    fn looped_samples_wrap_even_when_a_step_skips_the_loop() {
+        // Silence around a short loop, so every output sample after the
+        // start must come from inside the loop.
+        let mut samples = vec![0i16; 100];
+        samples[20..25].iter_mut().for_each(|s| *s = 16384);
+        let sf = SoundFont::parse(&font(&samples, (20, 24), 1)).unwrap();
+        // Four octaves up: 16 samples per step over a 4 sample loop.
+        let note = drum(108);
+        let (sample, zone) = sf.regions(&note).remove(0);
+        let out = sf.render_region(sample, &zone, &note, RATE);
+        assert!(out.len() as f64 >= GATE_SECS * RATE as f64);
+        let settled = (0.01 * RATE as f64) as usize;
+        let held = (GATE_SECS * RATE as f64) as usize;
+        for (i, v) in out[settled..held].iter().enumerate() {
+            assert!((v - 0.25).abs() < 1e-3, "sample {}: {}", settled + i, v);
+        }
+
+        // Without looping the same note runs off the end almost at once.
+        let sf = SoundFont::parse(&font(&samples, (20, 24), 0)).unwrap();
+        let (sample, zone) = sf.regions(&note).remove(0);
+        assert!(sf.render_region(sample, &zone, &note, RATE).len() < 10);
+    }
+
+    #[test]
+    fn note_specs() {
+        let fallback = drum(76);
+        let n = parse_note("75", fallback).unwrap();
+        assert_eq!((n.bank, n.program, n.key, n.velocity), (128, 0, 75, 127));
+        let n = parse_note("0:115@100", fallback).unwrap();
+        assert_eq!((n.bank, n.program, n.key, n.velocity), (128, 0, 115, 100));
+        let n = parse_note(" 1 : 2 : 3 @ 4 ", fallback).unwrap();
+        assert_eq!((n.bank, n.program, n.key, n.velocity), (1, 2, 3, 4));
+        let n = parse_note("127@1", fallback).unwrap();
+        assert_eq!((n.key, n.velocity), (127, 1));
+        for bad in [
+            "", "128", "75@0", "75@128", "1:2:3:4", "a:b", "75@", "@100", "-1",
+        ] {
+            assert!(parse_note(bad, fallback).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,304 @@
+use crate::routing::Layer;
+
+/// What is said on a tick.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Word {
+    /// A beat of the bar, counted from one.
+    Number(u8),
+    And,
+    E,
+    A,
+}
+
+/// The word for a tick: the beat number on the beat, then "and" for
+/// eighths, "and a" for triplets and "e and a" for sixteenths.
+pub fn count_word(beat_in_bar: u8, tick_in_beat: u8, ticks_per_beat: u8) -> Word {
+    match (ticks_per_beat, tick_in_beat) {
+        (_, 0) => Word::Number(beat_in_bar),
+        (4, 1) => Word::E,
+        (3, 2) | (4, 3) => Word::A,
+        _ => Word::And,
+    }
+}
+
+/// Numbers below twenty, in ARPAbet.
+const ONES: [&str; 20] = [
+    "",
+    "W AH N",
+    "T UW",
+    "TH R IY",
+    "F AO R",
+    "F AY V",
+    "S IH K S",
+    "S EH V AH N",
+    "EY T",
+    "N AY N",
+    "T EH N",
+    "IH L EH V AH N",
+    "T W EH L V",
+    "TH ER T IY N",
+    "F AO R T IY N",
+    "F IH F T IY N",
+    "S IH K S T IY N",
+    "S EH V AH N T IY N",
+    "EY T IY N",
+    "N AY N T IY N",
+];
+
+const TENS: [&str; 10] = [
+    "",
+    "",
+    "T W EH N T IY",
+    "TH ER T IY",
+    "F AO R T IY",
+    "F IH F T IY",
+    "S IH K S T IY",
+    "S EH V AH N T IY",
+    "EY T IY",
+    "N AY N T IY",
+];
+
+/// Longest a spoken number may last, so counts keep up with the beat;
+/// longer words are spoken faster.
+const MAX_NUMBER_SECS: f32 = 0.36;
+const MAX_SYLLABLE_SECS: f32 = 0.15;
+
+fn phonemes(word: Word) -> String {
+    match word {
+        Word::Number(n) if n < 20 => ONES[n as usize].to_string(),
+        Word::Number(n) if n < 100 => {
+            format!("{} {}", TENS[n as usize / 10], ONES[n as usize % 10])
+        }
+        // Nobody counts bars that long aloud.
+        Word::Number(_) => String::new(),
+        Word::And => "AE N".to_string(),
+        Word::E => "IY".to_string(),
+        Word::A => "AH".to_string(),
+    }
+}
+
+/// One steady stretch of a phoneme, approached from the previous one.
+#[derive(Copy, Clone)]
+struct Phone {
+    /// F1–F3 in Hz; `None` takes the next phone's, as in the aspiration
+    /// after a stop.
+    formants: Option<[f32; 3]>,
+    voice: f32,
+    /// Breath noise through the formants.
+    aspiration: f32,
+    /// Hiss alongside the formants, centered on `hiss` Hz.
+    frication: f32,
+    hiss: f32,
+    secs: f32,
+}
+
+const SILENT: Phone = Phone {
+    formants: None,
+    voice: 0.0,
+    aspiration: 0.0,
+    frication: 0.0,
+    hiss: 4000.0,
+    secs: 0.0,
+};
+
+fn vowel(f1: f32, f2: f32, f3: f32, ms: f32) -> Phone {
+    Phone {
+        formants: Some([f1, f2, f3]),
+        voice: 1.0,
+        secs: ms / 1000.0,
+        ..SILENT
+    }
+}
+
+fn voiced(f1: f32, f2: f32, f3: f32, voice: f32, ms: f32) -> Phone {
+    Phone {
+        voice,
+        ..vowel(f1, f2, f3, ms)
+    }
+}
+
+fn hiss(frication: f32, hiss: f32, ms: f32) -> Phone {
+    Phone {
+        frication,
+        hiss,
+        secs: ms / 1000.0,
+        ..SILENT
+    }
+}
+
+/// A stop: silence, a burst of hiss, then breath into the next sound.
+fn stop(closure_ms: f32, burst: f32, at: f32, aspiration: f32) -> [Phone; 3] {
+    [
+        Phone {
+            secs: closure_ms / 1000.0,
+            ..SILENT
+        },
+        hiss(burst, at, 12.0),
+        Phone {
+            aspiration,
+            secs: 0.035,
+            ..SILENT
+        },
+    ]
+}
+
+fn phones_for(phoneme: &str) -> Vec<Phone> {
+    match phoneme {
+        "IY" => vec![vowel(270.0, 2290.0, 3010.0, 110.0)],
+        "IH" => vec![vowel(390.0, 1990.0, 2550.0, 80.0)],
+        "EH" => vec![vowel(530.0, 1840.0, 2480.0, 90.0)],
+        "AE" => vec![vowel(660.0, 1720.0, 2410.0, 110.0)],
+        "AH" => vec![vowel(600.0, 1170.0, 2390.0, 90.0)],
+        "AO" => vec![vowel(570.0, 840.0, 2410.0, 120.0)],
+        "UW" => vec![vowel(300.0, 870.0, 2240.0, 130.0)],
+        "ER" => vec![vowel(490.0, 1350.0, 1690.0, 110.0)],
+        "AY" => vec![
+            vowel(730.0, 1090.0, 2440.0, 90.0),
+            vowel(330.0, 2000.0, 2800.0, 70.0),
+        ],
+        "EY" => vec![
+            vowel(480.0, 1900.0, 2500.0, 80.0),
+            vowel(330.0, 2200.0, 2900.0, 60.0),
+        ],
+        "W" => vec![voiced(290.0, 610.0, 2150.0, 0.8, 50.0)],
+        "R" => vec![voiced(310.0, 1060.0, 1380.0, 0.8, 60.0)],
+        "L" => vec![voiced(310.0, 1050.0, 2880.0, 0.8, 60.0)],
+        "N" => vec![voiced(250.0, 1700.0, 2600.0, 0.5, 70.0)],
+        "V" => vec![Phone {
+            frication: 0.12,
+            hiss: 7000.0,
+            ..voiced(220.0, 1100.0, 2080.0, 0.45, 60.0)
+        }],
+        "S" => vec![hiss(0.5, 5500.0, 100.0)],
+        "F" => vec![hiss(0.25, 7000.0, 90.0)],
+        "TH" => vec![hiss(0.18, 6000.0, 80.0)],
+        "T" => stop(40.0, 0.6, 4500.0, 0.4).to_vec(),
+        "K" => stop(45.0, 0.5, 2500.0, 0.4).to_vec(),
+        _ => Vec::new(),
+    }
+}
+
+/// Two-pole resonator with unity gain at DC (Klatt, 1980).
+#[derive(Default)]
+struct Resonator {
+    y1: f32,
+    y2: f32,
+}
+
+impl Resonator {
+    fn process(&mut self, x: f32, freq: f32, bandwidth: f32, rate: f32) -> f32 {
+        let r = (-std::f32::consts::PI * bandwidth / rate).exp();
+        let c = -r * r;
+        let b = 2.0 * r * (std::f32::consts::TAU * freq / rate).cos();
+        let y = (1.0 - b - c) * x + b * self.y1 + c * self.y2;
+        self.y2 = self.y1;
+        self.y1 = y;
+        y
+    }
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+/// Says `word` with a small formant synthesizer: a glottal pulse and breath
+/// noise through three formants, plus hiss for the fricatives. Accents are
+/// said higher and louder, subdivisions softer.
+pub fn render(word: Word, layer: Layer, sample_rate: u32) -> Vec<f32> {
+    let mut phones: Vec<Phone> = phonemes(word)
+        .split_whitespace()
+        .flat_map(phones_for)
+        .collect();
+    // Start on the first sound, not on a stop's closure.
+    let sounding = phones
+        .iter()
+        .position(|p| p.voice + p.aspiration + p.frication > 0.0)
+        .unwrap_or(phones.len());
+    phones.drain(..sounding);
+    // Fill in formants from the sound a phone leads into.
+    let mut next = [500.0, 1500.0, 2500.0];
+    for phone in phones.iter_mut().rev() {
+        next = *phone.formants.get_or_insert(next);
+    }
+    let total: f32 = phones.iter().map(|p| p.secs).sum();
+    let max = match word {
+        Word::Number(_) => MAX_NUMBER_SECS,
+        _ => MAX_SYLLABLE_SECS,
+    };
+    let pace = (max / total.max(f32::EPSILON)).min(1.0);
+    let (pitch, level) = match layer {
+        Layer::Accent => ((150.0, 115.0), 0.5),
+        Layer::Beat => ((125.0, 100.0), 0.4),
+        Layer::Sub => ((115.0, 95.0), 0.25),
+    };
+
+    let rate = sample_rate as f32;
+    let mut out = Vec::new();
+    let mut formants = [
+        Resonator::default(),
+        Resonator::default(),
+        Resonator::default(),
+    ];
+    let mut fricative = Resonator::default();
+    let (mut phase, mut flow) = (0.0f32, 0.0f32);
+    let (mut noise, mut last_noise) = (0x2545_f491u32, 0.0f32);
+    let mut last_voiced = 0.0f32;
+    let mut prev = phones.first().copied().unwrap_or(SILENT);
+    for phone in &phones {
+        let len = (phone.secs * pace * rate) as usize;
+        // Formants glide in; loudness changes quickly, keeping stops crisp.
+        let glide = (len as f32 * 0.4).min(0.04 * rate).max(1.0);
+        let fade = (len as f32 * 0.5).min(0.01 * rate).max(1.0);
+        let (from, to) = (prev.formants.unwrap(), phone.formants.unwrap());
+        for i in 0..len {
+            let g = (i as f32 / glide).min(1.0);
+            let a = (i as f32 / fade).min(1.0);
+            let t = out.len() as f32 / rate / (total * pace);
+            let f0 = lerp(pitch.0, pitch.1, t);
+
+            // Rosenberg glottal pulse; its slope is the excitation.
+            phase = (phase + f0 / rate).fract();
+            let pulse = if phase < 0.4 {
+                0.5 * (1.0 - (std::f32::consts::PI * phase / 0.4).cos())
+            } else if phase < 0.56 {
+                (std::f32::consts::FRAC_PI_2 * (phase - 0.4) / 0.16).cos()
+            } else {
+                0.0
+            };
+            let excitation = (pulse - flow) * rate / f0 * 0.1;
+            flow = pulse;
+
+            noise ^= noise << 13;
+            noise ^= noise >> 17;
+            noise ^= noise << 5;
+            let white = noise as f32 / u32::MAX as f32 * 2.0 - 1.0;
+
+            let mut s = excitation * lerp(prev.voice, phone.voice, a)
+                + white * lerp(prev.aspiration, phone.aspiration, a);
+            for (k, (r, bw)) in formants.iter_mut().zip([70.0, 100.0, 150.0]).enumerate() {
+                s = r.process(s, lerp(from[k], to[k], g), bw, rate);
+            }
+            // Differenced noise tilts the hiss towards the highs; the
+            // resonator's gain there is scaled back to the voice's.
+            let hiss = 0.1 * fricative.process(white - last_noise, phone.hiss, 2000.0, rate);
+            last_noise = white;
+            // Radiation from the lips lifts the highs.
+            let radiated = s - 0.94 * last_voiced;
+            last_voiced = s;
+            out.push(radiated + hiss * lerp(prev.frication, phone.frication, a));
+        }
+        prev = *phone;
+    }
+
+    // Normalize, with short fades against clicks at either end.
+    let peak = out.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+    if peak > 0.0 {
+        let edge = (0.005 * rate) as usize;
+        let len = out.len();
+        for (i, s) in out.iter_mut().enumerate() {
+            let fade = (i.min(len - 1 - i) as f32 / edge as f32).min(1.0);
+            *s *= level / peak * fade;
+        }
+    }
+    out
+}
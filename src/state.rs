@@ -0,0 +1,96 @@
+use std::path::PathBuf;
+
+use clap::ValueEnum;
+
+use crate::cli::{Cli, Subdivision};
+use crate::config::{self, Config, parse_config};
+use crate::tempo::{parse_signature, parse_speed, parse_swing};
+
+/// Where the last session is kept, next to the config file.
+pub fn state_path() -> Option<PathBuf> {
+    config::config_dir().map(|dir| dir.join("state"))
+}
+
+/// The saved session, in the config file's `key = value` format; a missing
+/// file is an empty state.
+pub fn load() -> Result<Config, String> {
+    let Some(path) = state_path() else {
+        return Ok(Config::default());
+    };
+    match std::fs::read_to_string(&path) {
+        Ok(text) => parse_config(&text).map_err(|e| format!("{}: {}", path.display(), e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+        Err(e) => Err(format!("could not read {}: {}", path.display(), e)),
+    }
+}
+
+/// Writes `values` to the state file; keys not given keep their saved value.
+pub fn save(values: &[(&str, String)]) -> Result<PathBuf, String> {
+    let path = state_path().ok_or("cannot locate the config directory (HOME is not set)")?;
+    let old = load().unwrap_or_default();
+    let mut lines: Vec<String> = old
+        .keys()
+        .filter(|key| !values.iter().any(|(k, _)| k == key))
+        .filter_map(|key| old.get(key).map(|value| format!("{} = {}", key, value)))
+        .collect();
+    lines.extend(values.iter().map(|(k, v)| format!("{} = {}", k, v)));
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| format!("could not create {}: {}", dir.display(), e))?;
+    }
+    let mut out = lines.join("\n");
+    out.push('\n');
+    std::fs::write(&path, out).map_err(|e| format!("could not write {}: {}", path.display(), e))?;
+    Ok(path)
+}
+
+/// Applies the saved tempo, signature, subdivision, speed and swing to `cli`,
+/// except where `explicit` says the option was given on the command line.
+/// Returns a warning for each saved value that is no longer valid.
+pub fn apply(state: &Config, cli: &mut Cli, explicit: impl Fn(&str) -> bool) -> Vec<String> {
+    let mut warnings = Vec::new();
+    let mut invalid = |key: &str, value: &str| {
+        warnings.push(format!("ignoring saved {} \"{}\"", key, value));
+    };
+    if !explicit("bpm") && !explicit("bpm_positional") {
+        if let Some(value) = state.get("bpm") {
+            match value.parse::<u16>() {
+                Ok(bpm) if (20..=400).contains(&bpm) => cli.bpm = bpm,
+                _ => invalid("bpm", value),
+            }
+        }
+    }
+    if !explicit("signature") {
+        if let Some(value) = state.get("signature") {
+            match parse_signature(value) {
+                Ok(_) => cli.signature = value.to_string(),
+                Err(_) => invalid("signature", value),
+            }
+        }
+    }
+    if !explicit("subdivision") {
+        if let Some(value) = state.get("subdivision") {
+            match Subdivision::from_str(value, true) {
+                Ok(subdivision) => cli.subdivision = subdivision,
+                Err(_) => invalid("subdivision", value),
+            }
+        }
+    }
+    if !explicit("speed") {
+        if let Some(value) = state.get("speed") {
+            match parse_speed(value) {
+                Ok(speed) => cli.speed = speed,
+                Err(_) => invalid("speed", value),
+            }
+        }
+    }
+    if !explicit("swing") {
+        if let Some(value) = state.get("swing") {
+            match parse_swing(value) {
+                Ok(swing) => cli.swing = swing,
+                Err(_) => invalid("swing", value),
+            }
+        }
+    }
+    warnings
+}
@@ -0,0 +1,195 @@
+use crate::mixer::CHANNELS;
+
+/// Analysis window length in seconds.
+const WINDOW_SECS: f64 = 0.04;
+/// How far either side of the nominal position to search for a match.
+const TOLERANCE_SECS: f64 = 0.01;
+/// Sample stride used when comparing candidate segments.
+const CORR_STRIDE: usize = 4;
+
+/// Streaming WSOLA time-stretcher: changes playback speed without changing
+/// pitch by overlap-adding windowed segments of the source, each picked
+/// within a small tolerance so it lines up with the previous one.
+pub struct Stretcher {
+    window: usize,
+    hop: usize,
+    tolerance: usize,
+    weights: Vec<f32>,
+    /// Overlap-add accumulator, `window` interleaved frames.
+    acc: Vec<f32>,
+    ready: Vec<f32>,
+    ready_pos: usize,
+    ana_pos: f64,
+    prev: Option<usize>,
+}
+
+impl Stretcher {
+    pub fn new(sample_rate: u32) -> Self {
+        let window = ((sample_rate as f64 * WINDOW_SECS) as usize / 2 * 2).max(64);
+        let ch = CHANNELS as usize;
+        // Periodic Hann: windows at 50% overlap sum to exactly one.
+        let weights = (0..window)
+            .map(|i| {
+                let x = std::f32::consts::TAU * i as f32 / window as f32;
+                0.5 - 0.5 * x.cos()
+            })
+            .collect();
+        Stretcher {
+            window,
+            hop: window / 2,
+            tolerance: (sample_rate as f64 * TOLERANCE_SECS) as usize,
+            weights,
+            acc: vec![0.0; window * ch],
+            ready: Vec::new(),
+            ready_pos: 0,
+            ana_pos: 0.0,
+            prev: None,
+        }
+    }
+
+    /// Restarts stretching from source frame `pos`, e.g. after a speed change.
+    pub fn reset(&mut self, pos: usize) {
+        self.acc.iter_mut().for_each(|s| *s = 0.0);
+        self.ready.clear();
+        self.ready_pos = 0;
+        self.ana_pos = pos as f64;
+        self.prev = None;
+    }
+
+    /// Source frame being played. The analysis position runs up to a hop
+    /// ahead of it, so resuming from that would skip audio.
+    pub fn position(&self) -> usize {
+        match self.prev {
+            // The ready frames fade in the segment taken at `prev`.
+            Some(prev) => prev + self.ready_pos / CHANNELS as usize,
+            None => self.ana_pos as usize,
+        }
+    }
+
+    /// Fills `out` (interleaved) from `src` played at `speed`; returns the
+    /// number of samples written, less than `out.len()` at the end of `src`.
+    pub fn fill(&mut self, src: &[f32], speed: f64, out: &mut [f32]) -> usize {
+        let mut written = 0;
+        while written < out.len() {
+            if self.ready_pos >= self.ready.len() && !self.next_hop(src, speed) {
+                break;
+            }
+            let n = (out.len() - written).min(self.ready.len() - self.ready_pos);
+            out[written..written + n]
+                .copy_from_slice(&self.ready[self.ready_pos..self.ready_pos + n]);
+            written += n;
+            self.ready_pos += n;
+        }
+        written
+    }
+
+    fn next_hop(&mut self, src: &[f32], speed: f64) -> bool {
+        let ch = CHANNELS as usize;
+        let frames = src.len() / ch;
+        let nominal = self.ana_pos.round() as usize;
+        if nominal + self.window + self.tolerance >= frames {
+            return false;
+        }
+        let mono = |frame: usize| -> f32 { src[frame * ch..frame * ch + ch].iter().sum() };
+        let best = match self.prev {
+            Some(prev) if prev + self.hop + self.window < frames => {
+                // Match against the natural continuation of the last segment.
+                let natural = prev + self.hop;
+                let lo = nominal.saturating_sub(self.tolerance);
+                let hi = nominal + self.tolerance;
+                let mut best = nominal;
+                let mut best_corr = f32::MIN;
+                for cand in (lo..=hi).step_by(2) {
+                    let corr: f32 = (0..self.window)
+                        .step_by(CORR_STRIDE)
+                        .map(|i| mono(cand + i) * mono(natural + i))
+                        .sum();
+                    if corr > best_corr {
+                        best_corr = corr;
+                        best = cand;
+                    }
+                }
+                best
+            }
+            _ => nominal,
+        };
+
+        for i in 0..self.window {
+            let w = self.weights[i];
+            for c in 0..ch {
+                self.acc[i * ch + c] += w * src[(best + i) * ch + c];
+            }
+        }
+        let hop_samples = self.hop * ch;
+        self.ready.clear();
+        self.ready.extend_from_slice(&self.acc[..hop_samples]);
+        self.ready_pos = 0;
+        self.acc.drain(..hop_samples);
+        self.acc.resize(self.window * ch, 0.0);
+
+        self.prev = Some(best);
+        self.ana_pos += self.hop as f64 * speed;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 8000;
+
+    /// Two seconds of interleaved noise, different on each channel.
+    fn noise() -> Vec<f32> {
+        let mut state = 12345u32;
+        (0..2 * RATE as usize * CHANNELS as usize)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (state >> 8) as f32 / (1 << 23) as f32 - 1.0
+            })
+            .collect()
+    }
+
+    fn stretch(src: &[f32], speed: f64) -> Vec<f32> {
+        let mut stretcher = Stretcher::new(RATE);
+        let mut out = Vec::new();
+        let mut block = vec![0.0; 512 * CHANNELS as usize];
+        loop {
+            let n = stretcher.fill(src, speed, &mut block);
+            out.extend_from_slice(&block[..n]);
+            if n < block.len() {
+                return out;
+            }
+        }
+    }
+
+    #[test]
+    fn output_length_follows_the_speed() {
+        let src = noise();
+        for speed in [0.5, 1.0, 1.5] {
+            let out = stretch(&src, speed);
+            let expected = src.len() as f64 / speed;
+            let error = (out.len() as f64 - expected).abs() / expected;
+            assert!(
+                error < 0.05,
+                "{}: {} samples for {}",
+                speed,
+                out.len(),
+                src.len()
+            );
+        }
+    }
+
+    #[test]
+    fn full_speed_passes_the_input_through() {
+        let src = noise();
+        let out = stretch(&src, 1.0);
+        // The first hop fades in; after that every segment is taken
+        // where it was and the windows sum to one.
+        let skip = Stretcher::new(RATE).window * CHANNELS as usize;
+        assert!(out.len() > skip);
+        for (i, (o, s)) in out.iter().zip(&src).enumerate().skip(skip) {
+            assert!((o - s).abs() < 1e-4, "sample {}: {} vs {}", i, o, s);
+        }
+    }
+}
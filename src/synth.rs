@@ -0,0 +1,237 @@
+use crate::config::Config;
+use crate::routing::Layer;
+
+/// Prefix of config sections that define a voice, as in `[voice.name]`.
+const SECTION_PREFIX: &str = "voice.";
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Wave {
+    Sine,
+    Square,
+    Triangle,
+    Saw,
+    Noise,
+}
+
+/// A click voice synthesized from parameters in the config file.
+#[derive(Clone, Debug)]
+pub struct SynthVoice {
+    pub name: String,
+    pub wave: Wave,
+    /// Base frequency in Hz of beats, accents and subdivisions.
+    pub freq: f32,
+    pub accent_freq: f32,
+    pub sub_freq: f32,
+    /// Semitones above the base the pitch starts at, gliding down.
+    pub pitch_sweep: f32,
+    /// Time constant of the pitch glide in seconds.
+    pub pitch_time: f32,
+    /// Amplitude envelope, times in seconds and sustain as a level.
+    pub attack: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    pub release: f32,
+    /// Low-pass cutoff in Hz; none leaves the sound unfiltered.
+    pub cutoff: Option<f32>,
+    /// Filter Q; 0.707 is flat, higher values ring at the cutoff.
+    pub resonance: f32,
+    /// Total length in seconds, release included.
+    pub length: f32,
+    /// Peak level, 0 to 1.
+    pub level: f32,
+}
+
+fn parse_wave(s: &str) -> Result<Wave, String> {
+    match s.to_lowercase().as_str() {
+        "sine" => Ok(Wave::Sine),
+        "square" => Ok(Wave::Square),
+        "triangle" => Ok(Wave::Triangle),
+        "saw" => Ok(Wave::Saw),
+        "noise" => Ok(Wave::Noise),
+        _ => Err(format!(
+            "unknown wave \"{}\" (sine, square, triangle, saw, noise)",
+            s
+        )),
+    }
+}
+
+impl SynthVoice {
+    /// Reads the `[voice.<name>]` section; every key is optional.
+    fn from_config(config: &Config, name: &str) -> Result<SynthVoice, String> {
+        let section = format!("{}{}", SECTION_PREFIX, name);
+        let num = |key: &str, default: f32, min: f32, max: f32| -> Result<f32, String> {
+            let Some(value) = config.get_in(&section, key) else {
+                return Ok(default);
+            };
+            match value.parse::<f32>() {
+                Ok(v) if (min..=max).contains(&v) => Ok(v),
+                _ => Err(format!(
+                    "{} must be a number from {} to {}, got \"{}\"",
+                    key, min, max, value
+                )),
+            }
+        };
+        let ms = |key: &str, default: f32| num(key, default, 0.0, 2000.0).map(|v| v / 1000.0);
+        let wave = match config.get_in(&section, "wave") {
+            Some(w) => parse_wave(w)?,
+            None => Wave::Sine,
+        };
+        let freq = num("freq", 1320.0, 20.0, 20000.0)?;
+        let cutoff = match config.get_in(&section, "cutoff") {
+            Some(_) => Some(num("cutoff", 0.0, 20.0, 20000.0)?),
+            None => None,
+        };
+        let voice = SynthVoice {
+            name: name.to_string(),
+            wave,
+            freq,
+            // A fourth up, like the built-in click.
+            accent_freq: num("accent_freq", freq * 4.0 / 3.0, 20.0, 20000.0)?,
+            sub_freq: num("sub_freq", freq, 20.0, 20000.0)?,
+            pitch_sweep: num("pitch_sweep", 0.0, -48.0, 48.0)?,
+            pitch_time: ms("pitch_ms", 10.0)?,
+            attack: ms("attack_ms", 1.0)?,
+            decay: ms("decay_ms", 20.0)?,
+            sustain: num("sustain", 0.0, 0.0, 1.0)?,
+            release: ms("release_ms", 5.0)?,
+            cutoff,
+            resonance: num("resonance", 0.707, 0.1, 20.0)?,
+            length: ms("length_ms", 30.0)?,
+            level: num("level", 0.2, 0.0, 1.0)?,
+        };
+        if voice.length <= 0.0 {
+            return Err("length_ms must be more than 0".to_string());
+        }
+        Ok(voice)
+    }
+
+    fn base_freq(&self, layer: Layer) -> f32 {
+        match layer {
+            Layer::Accent => self.accent_freq,
+            Layer::Beat => self.freq,
+            Layer::Sub => self.sub_freq,
+        }
+    }
+
+    /// Amplitude at `t` seconds: linear attack, decay to the sustain level,
+    /// and a release that ends with the voice.
+    fn envelope(&self, t: f32) -> f32 {
+        let release_start = (self.length - self.release).max(0.0);
+        let held = |t: f32| {
+            if t < self.attack {
+                t / self.attack
+            } else if t < self.attack + self.decay {
+                1.0 - (1.0 - self.sustain) * (t - self.attack) / self.decay
+            } else {
+                self.sustain
+            }
+        };
+        if t < release_start {
+            held(t)
+        } else {
+            held(release_start) * (1.0 - (t - release_start) / self.release.max(1e-6)).max(0.0)
+        }
+    }
+
+    pub fn render(&self, layer: Layer, sample_rate: u32) -> Vec<f32> {
+        let rate = sample_rate as f32;
+        let len = (self.length * rate) as usize;
+        let base = self.base_freq(layer);
+        let mut filter = self
+            .cutoff
+            .map(|c| Biquad::lowpass(c, self.resonance, rate));
+        let mut phase = 0.0f32;
+        let mut noise = 0x2545_f491u32;
+        let mut out = Vec::with_capacity(len);
+        for i in 0..len {
+            let t = i as f32 / rate;
+            let glide = if self.pitch_time > 0.0 {
+                self.pitch_sweep * (-t / self.pitch_time).exp()
+            } else {
+                0.0
+            };
+            let freq = base * 2f32.powf(glide / 12.0);
+            let osc = match self.wave {
+                Wave::Sine => (std::f32::consts::TAU * phase).sin(),
+                Wave::Square => {
+                    if phase < 0.5 {
+                        1.0
+                    } else {
+                        -1.0
+                    }
+                }
+                Wave::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+                Wave::Saw => 2.0 * phase - 1.0,
+                Wave::Noise => {
+                    noise ^= noise << 13;
+                    noise ^= noise >> 17;
+                    noise ^= noise << 5;
+                    noise as f32 / u32::MAX as f32 * 2.0 - 1.0
+                }
+            };
+            phase = (phase + freq / rate).fract();
+            let s = match filter.as_mut() {
+                Some(f) => f.process(osc),
+                None => osc,
+            };
+            out.push(s * self.envelope(t) * self.level);
+        }
+        out
+    }
+}
+
+/// Second-order low-pass filter (RBJ cookbook).
+struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    x1: f32,
+    x2: f32,
+    y1: f32,
+    y2: f32,
+}
+
+impl Biquad {
+    fn lowpass(cutoff: f32, q: f32, rate: f32) -> Biquad {
+        let w = std::f32::consts::TAU * cutoff.min(rate * 0.45) / rate;
+        let alpha = w.sin() / (2.0 * q);
+        let cos = w.cos();
+        let a0 = 1.0 + alpha;
+        Biquad {
+            b0: (1.0 - cos) / 2.0 / a0,
+            b1: (1.0 - cos) / a0,
+            b2: (1.0 - cos) / 2.0 / a0,
+            a1: -2.0 * cos / a0,
+            a2: (1.0 - alpha) / a0,
+            x1: 0.0,
+            x2: 0.0,
+            y1: 0.0,
+            y2: 0.0,
+        }
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        let y = self.b0 * x + self.b1 * self.x1 + self.b2 * self.x2
+            - self.a1 * self.y1
+            - self.a2 * self.y2;
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+        y
+    }
+}
+
+/// Every `[voice.<name>]` section of the config, in file order.
+pub fn load_voices(config: &Config) -> Result<Vec<SynthVoice>, String> {
+    config
+        .sections()
+        .into_iter()
+        .filter_map(|s| s.strip_prefix(SECTION_PREFIX))
+        .map(|name| {
+            SynthVoice::from_config(config, name).map_err(|e| format!("[voice.{}] {}", name, e))
+        })
+        .collect()
+}
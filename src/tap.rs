@@ -1,3 +1,4 @@
+use std::collections::VecDeque;
 use std::io::Write;
 use std::time::{Duration, Instant};
 
@@ -8,10 +9,186 @@ use crossterm::{
 };
 use unicode_width::UnicodeWidthStr;
 
-pub fn tap_tempo_blocking() -> Option<u16> {
-    let mut taps: Vec<Instant> = Vec::new();
+/// A gap longer than this before the second tap starts a new sequence.
+const RESET_GAP_SECS: f64 = 3.5;
+/// Once a tempo is established, a gap this many times the median resets.
+const RESET_GAP_FACTOR: f64 = 2.5;
+/// Intervals further than this fraction from the median are rejected.
+const OUTLIER_TOLERANCE: f64 = 0.2;
+
+/// Result of a tap tempo estimate.
+pub struct TapStats {
+    pub bpm: f64,
+    /// Intervals that went into the estimate, after folding.
+    pub intervals_ms: Vec<f64>,
+    /// Intervals dropped as outliers.
+    pub rejected: usize,
+    /// Intervals recognised as a skipped or doubled tap and rescaled.
+    pub folded: usize,
+    pub stdev_ms: f64,
+    /// 0.0..=1.0, from interval consistency and how many intervals were used.
+    pub confidence: f64,
+}
+
+impl TapStats {
+    pub fn rounded_bpm(&self) -> u16 {
+        (self.bpm.round() as u16).clamp(20, 400)
+    }
+
+    pub fn to_json(&self) -> String {
+        let intervals: Vec<String> = self
+            .intervals_ms
+            .iter()
+            .map(|v| format!("{:.3}", v))
+            .collect();
+        format!(
+            "{{\"bpm\":{:.3},\"rounded_bpm\":{},\"intervals_ms\":[{}],\"stdev_ms\":{:.3},\"rejected\":{},\"folded\":{},\"confidence\":{:.3}}}",
+            self.bpm,
+            self.rounded_bpm(),
+            intervals.join(","),
+            self.stdev_ms,
+            self.rejected,
+            self.folded,
+            self.confidence
+        )
+    }
+}
+
+/// Rolling tap tempo estimator shared by the tap screen and the live `t` key.
+///
+/// Keeps the last `window` intervals, resets itself after a long gap, folds
+/// half/double-time taps back onto the running tempo and averages the
+/// intervals that stay close to the median.
+pub struct TapTempo {
+    last: Option<Instant>,
+    intervals: VecDeque<f64>,
+    taps: usize,
+    window: usize,
+}
+
+impl TapTempo {
+    pub fn new(window: usize) -> Self {
+        let window = window.max(2);
+        TapTempo {
+            last: None,
+            intervals: VecDeque::with_capacity(window),
+            taps: 0,
+            window,
+        }
+    }
+
+    pub fn tap(&mut self, at: Instant) {
+        if let Some(last) = self.last {
+            let gap = at.saturating_duration_since(last).as_secs_f64();
+            let limit = self
+                .median()
+                .map(|m| m * RESET_GAP_FACTOR)
+                .unwrap_or(RESET_GAP_SECS);
+            if gap > limit {
+                self.reset();
+            } else {
+                if self.intervals.len() == self.window {
+                    self.intervals.pop_front();
+                }
+                self.intervals.push_back(gap);
+            }
+        }
+        self.last = Some(at);
+        self.taps = (self.taps + 1).min(self.window + 1);
+    }
+
+    pub fn reset(&mut self) {
+        self.last = None;
+        self.intervals.clear();
+        self.taps = 0;
+    }
+
+    /// Taps in the current sequence (capped at `window + 1`).
+    pub fn len(&self) -> usize {
+        self.taps
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.taps == 0
+    }
+
+    pub fn bpm(&self) -> Option<u16> {
+        self.stats().map(|s| s.rounded_bpm())
+    }
+
+    pub fn stats(&self) -> Option<TapStats> {
+        let median = self.median()?;
+        if median <= 0.0 {
+            return None;
+        }
+        let mut folded = 0;
+        let mut kept = Vec::with_capacity(self.intervals.len());
+        // The interval nearest the median is kept even when all are off,
+        // as with an even count split far either side of it.
+        let mut nearest = f64::NAN;
+        for &iv in &self.intervals {
+            let ratio = iv / median;
+            let iv = if (1.8..=2.2).contains(&ratio) {
+                folded += 1;
+                iv / 2.0
+            } else if (0.45..=0.55).contains(&ratio) {
+                folded += 1;
+                iv * 2.0
+            } else {
+                iv
+            };
+            if ((iv - median) / median).abs() <= OUTLIER_TOLERANCE {
+                kept.push(iv);
+            }
+            if nearest.is_nan() || (iv - median).abs() < (nearest - median).abs() {
+                nearest = iv;
+            }
+        }
+        if kept.is_empty() {
+            kept.push(nearest);
+        }
+        let n = kept.len() as f64;
+        let mean = kept.iter().sum::<f64>() / n;
+        let var = kept.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n;
+        let stdev = var.sqrt();
+        let consistency = (1.0 - (stdev / mean) / 0.1).clamp(0.0, 1.0);
+        let coverage = kept.len().min(6) as f64 / 6.0;
+        Some(TapStats {
+            bpm: 60.0 / mean,
+            intervals_ms: kept.iter().map(|v| v * 1000.0).collect(),
+            rejected: self.intervals.len() - kept.len(),
+            folded,
+            stdev_ms: stdev * 1000.0,
+            confidence: consistency * coverage,
+        })
+    }
+
+    fn median(&self) -> Option<f64> {
+        if self.intervals.is_empty() {
+            return None;
+        }
+        let mut sorted: Vec<f64> = self.intervals.iter().copied().collect();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        let mid = sorted.len() / 2;
+        if sorted.len() % 2 == 0 {
+            Some((sorted[mid - 1] + sorted[mid]) / 2.0)
+        } else {
+            Some(sorted[mid])
+        }
+    }
+}
+
+/// Intervals the tap screen averages over; older taps roll out of it.
+const TAP_WINDOW: usize = 8;
+
+/// Runs the tap screen on stderr so stdout stays free for `tap --print`.
+pub fn tap_tempo_blocking() -> Option<TapStats> {
+    let mut taps = TapTempo::new(TAP_WINDOW);
+    // Taps since the last reset, which the estimator only counts up to
+    // its window.
+    let mut count = 0;
     let _ = terminal::enable_raw_mode();
-    let mut out = std::io::stdout();
+    let mut out = std::io::stderr();
     let _ = out.execute(crossterm::terminal::EnterAlternateScreen);
     let _ = out.execute(crossterm::terminal::DisableLineWrap);
     let _ = out.execute(crossterm::cursor::Hide);
@@ -20,36 +197,40 @@ pub fn tap_tempo_blocking() -> Option<u16> {
         let (w, h) = terminal::size().unwrap_or((80, 24));
         let mid_y = h / 2;
 
-        let est_bpm = if taps.len() >= 2 {
-            let mut intervals = Vec::new();
-            for w in taps.windows(2) {
-                if let [a, b] = w {
-                    intervals.push((*b - *a).as_secs_f64());
-                }
-            }
-            if intervals.is_empty() {
-                None
-            } else {
-                Some(
-                    (60.0 / (intervals.iter().sum::<f64>() / intervals.len() as f64)).round()
-                        as u16,
-                )
-            }
-        } else {
-            None
-        };
+        let stats = taps.stats();
 
         let _ = out.queue(Clear(ClearType::All));
         let title = "Tap tempo — <Space> tap, <Enter> accept, <Esc> cancel";
         let info = format!(
-            "Taps: {}/8   BPM: {}",
-            taps.len().min(8),
-            est_bpm.map(|v| v.to_string()).unwrap_or("--".to_string())
+            "Taps: {}   BPM: {}",
+            count,
+            stats
+                .as_ref()
+                .map(|s| format!("{:.1}", s.bpm))
+                .unwrap_or("--".to_string())
         );
-        let progress: String = (0..8)
-            .map(|i| if i < taps.len().min(8) { '●' } else { '○' })
+        let progress: String = (0..TAP_WINDOW)
+            .map(|i| if i < count { '●' } else { '○' })
             .collect();
-        let lines = [title.to_string(), info, progress];
+        let quality = match &stats {
+            Some(s) => {
+                let mut q = format!(
+                    "{} intervals   ±{:.1} ms   confidence {:.0}%",
+                    s.intervals_ms.len(),
+                    s.stdev_ms,
+                    s.confidence * 100.0
+                );
+                if s.rejected > 0 {
+                    q.push_str(&format!("   {} rejected", s.rejected));
+                }
+                if s.folded > 0 {
+                    q.push_str(&format!("   {} half/double", s.folded));
+                }
+                q
+            }
+            None => String::new(),
+        };
+        let lines = [title.to_string(), info, progress, quality];
         for (i, line) in lines.iter().enumerate() {
             let line_w = UnicodeWidthStr::width(line.as_str()) as u16;
             let x = if w > line_w { (w - line_w) / 2 } else { 0 };
@@ -75,32 +256,24 @@ pub fn tap_tempo_blocking() -> Option<u16> {
         let _ = out.flush();
 
         if let Ok(true) = event::poll(Duration::from_millis(16)) {
-            if let Ok(ev) = event::read() {
-                if let event::Event::Key(key) = ev {
-                    use crossterm::event::KeyCode;
-                    match key.code {
-                        KeyCode::Char('q') | KeyCode::Esc => {
-                            let _ = out.execute(crossterm::cursor::Show);
-                            let _ = out.execute(crossterm::terminal::EnableLineWrap);
-                            let _ = out.execute(crossterm::terminal::LeaveAlternateScreen);
-                            let _ = terminal::disable_raw_mode();
-                            return None;
-                        }
-                        KeyCode::Enter => {
-                            if taps.len() >= 4 {
-                                break;
-                            }
-                        }
-                        KeyCode::Char(' ') => {
-                            let now = Instant::now();
-                            taps.push(now);
-                            flash_until = Some(now + Duration::from_millis(150));
-                            if taps.len() >= 8 {
-                                break;
-                            }
-                        }
-                        _ => {}
+            if let Ok(event::Event::Key(key)) = event::read() {
+                use crossterm::event::KeyCode;
+                match key.code {
+                    KeyCode::Char('q') | KeyCode::Esc => {
+                        let _ = out.execute(crossterm::cursor::Show);
+                        let _ = out.execute(crossterm::terminal::EnableLineWrap);
+                        let _ = out.execute(crossterm::terminal::LeaveAlternateScreen);
+                        let _ = terminal::disable_raw_mode();
+                        return None;
+                    }
+                    KeyCode::Enter if taps.len() >= 4 => break,
+                    KeyCode::Char(' ') => {
+                        let now = Instant::now();
+                        taps.tap(now);
+                        count = if taps.len() == 1 { 1 } else { count + 1 };
+                        flash_until = Some(now + Duration::from_millis(150));
                     }
+                    _ => {}
                 }
             }
         }
@@ -112,16 +285,89 @@ pub fn tap_tempo_blocking() -> Option<u16> {
     if taps.len() < 4 {
         return None;
     }
-    let mut intervals = Vec::new();
-    for w in taps.windows(2) {
-        if let [a, b] = w {
-            intervals.push((*b - *a).as_secs_f64());
+    taps.stats()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Taps at `times` seconds after a common start.
+    fn tapped(window: usize, times: &[f64]) -> TapTempo {
+        let start = Instant::now();
+        let mut taps = TapTempo::new(window);
+        for &t in times {
+            taps.tap(start + Duration::from_secs_f64(t));
         }
+        taps
     }
-    if intervals.is_empty() {
-        return None;
+
+    #[test]
+    fn steady_taps_give_the_tempo() {
+        let stats = tapped(8, &[0.0, 0.5, 1.0, 1.5, 2.0]).stats().unwrap();
+        assert!((stats.bpm - 120.0).abs() < 1e-6);
+        assert_eq!(stats.rejected, 0);
+        assert_eq!(stats.folded, 0);
+    }
+
+    #[test]
+    fn outliers_are_rejected() {
+        let stats = tapped(8, &[0.0, 0.5, 1.0, 1.5, 2.2, 2.7]).stats().unwrap();
+        assert_eq!(stats.rejected, 1);
+        assert!((stats.bpm - 120.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn skipped_and_doubled_taps_fold_onto_the_tempo() {
+        let skipped = tapped(8, &[0.0, 0.5, 1.0, 2.0, 2.5]).stats().unwrap();
+        assert_eq!(skipped.folded, 1);
+        assert!((skipped.bpm - 120.0).abs() < 1e-6);
+        let doubled = tapped(8, &[0.0, 0.5, 1.0, 1.25, 1.5, 2.0]).stats().unwrap();
+        assert_eq!(doubled.folded, 2);
+        assert!((doubled.bpm - 120.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn an_even_split_keeps_the_interval_nearest_the_median() {
+        let stats = tapped(8, &[0.0, 0.3, 0.9]).stats().unwrap();
+        assert_eq!(stats.intervals_ms.len(), 1);
+        assert_eq!(stats.rejected, 1);
+    }
+
+    #[test]
+    fn long_gaps_start_over() {
+        // Before a tempo is set, after `RESET_GAP_SECS`.
+        let taps = tapped(8, &[0.0, 4.0]);
+        assert_eq!(taps.len(), 1);
+        assert!(taps.stats().is_none());
+        // Then after `RESET_GAP_FACTOR` times the median.
+        let taps = tapped(8, &[0.0, 0.5, 1.0, 2.3, 2.8]);
+        assert_eq!(taps.len(), 2);
+        assert_eq!(taps.stats().unwrap().intervals_ms.len(), 1);
+        // A gap within the factor is a skipped tap, not a reset.
+        assert_eq!(tapped(8, &[0.0, 0.5, 1.0, 2.2]).len(), 4);
+    }
+
+    #[test]
+    fn the_window_rolls() {
+        let times: Vec<f64> = (0..12).map(|i| i as f64 * 0.5).collect();
+        let taps = tapped(4, &times);
+        assert_eq!(taps.len(), 5);
+        assert_eq!(taps.stats().unwrap().intervals_ms.len(), 4);
+    }
+
+    #[test]
+    fn confidence_grows_with_taps_and_falls_with_spread() {
+        let steady = |n: usize| {
+            let times: Vec<f64> = (0..n).map(|i| i as f64 * 0.5).collect();
+            tapped(8, &times).stats().unwrap().confidence
+        };
+        assert!((steady(4) - 0.5).abs() < 1e-9);
+        assert!((steady(7) - 1.0).abs() < 1e-9);
+        assert!((steady(9) - 1.0).abs() < 1e-9);
+        let uneven = tapped(8, &[0.0, 0.48, 1.0, 1.47, 2.0, 2.52, 3.0])
+            .stats()
+            .unwrap();
+        assert!(uneven.confidence > 0.0 && uneven.confidence < 1.0);
     }
-    let avg = intervals.iter().sum::<f64>() / intervals.len() as f64;
-    let bpm = (60.0 / avg).round() as u16;
-    Some(bpm.clamp(20, 400))
 }
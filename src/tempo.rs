@@ -38,6 +38,104 @@ pub fn parse_duration_ms(src: &str) -> Result<u64, String> {
         .map_err(|_| "invalid duration".to_string())
 }
 
+/// Like `parse_duration_ms`, but a leading `-` gives a time before the start.
+pub fn parse_signed_duration_ms(src: &str) -> Result<i64, String> {
+    match src.strip_prefix('-') {
+        Some(rest) => parse_duration_ms(rest).map(|ms| -(ms as i64)),
+        None => parse_duration_ms(src).map(|ms| ms as i64),
+    }
+}
+
+/// Like `parse_duration_ms`, but the unit is required: a session length
+/// given as a bare number is more likely meant in minutes than in ms.
+/// Hours (`1.5h`) are accepted here too.
+pub fn parse_session_length(src: &str) -> Result<u64, String> {
+    if src.parse::<f64>().is_ok() {
+        return Err(format!("missing unit, e.g. {}m or {}s", src, src));
+    }
+    if src.trim_start().starts_with('-') {
+        return Err("session length must be longer than 0".to_string());
+    }
+    let ms = match src.strip_suffix('h') {
+        Some(s) => s
+            .parse::<f64>()
+            .map(|v| (v * 3_600_000.0) as u64)
+            .map_err(|_| "invalid hours".to_string())?,
+        None => parse_duration_ms(src)?,
+    };
+    if ms == 0 {
+        return Err("session length must be longer than 0".to_string());
+    }
+    Ok(ms)
+}
+
+/// Parses practice blocks such as `25m/5m` into playing and break time in ms.
+pub fn parse_blocks(src: &str) -> Result<(u64, u64), String> {
+    let (play, rest) = src
+        .split_once('/')
+        .ok_or("blocks must be in the form PLAY/BREAK, e.g. 25m/5m")?;
+    let play = parse_duration_ms(play.trim())?;
+    let rest = parse_duration_ms(rest.trim())?;
+    if play == 0 {
+        return Err("the playing part of a block must be longer than 0".to_string());
+    }
+    Ok((play, rest))
+}
+
+/// Parses a percentage such as `70%` or `70` into a factor (0.7).
+pub fn parse_percent(src: &str) -> Result<f64, String> {
+    let s = src.trim();
+    let s = s.strip_suffix('%').unwrap_or(s);
+    s.trim()
+        .parse::<f64>()
+        .map(|v| v / 100.0)
+        .map_err(|_| "invalid percentage".to_string())
+}
+
+pub fn parse_speed(src: &str) -> Result<f64, String> {
+    let speed = parse_percent(src)?;
+    if !(0.25..=1.5).contains(&speed) {
+        return Err("speed must be between 25% and 150%".to_string());
+    }
+    Ok(speed)
+}
+
+pub fn parse_swing(src: &str) -> Result<f64, String> {
+    let swing = parse_percent(src)?;
+    if !(0.5..=0.75).contains(&swing) {
+        return Err("swing must be between 50% (straight) and 75%".to_string());
+    }
+    Ok(swing)
+}
+
+/// Swing only pairs up eighths and sixteenths.
+fn swings(ticks_per_beat: u8) -> bool {
+    matches!(ticks_per_beat, 2 | 4)
+}
+
+/// Length of tick `tick` of a beat in straight ticks: the first of each pair
+/// takes `swing` of the pair, the second the rest.
+pub fn swung_length(tick: u8, ticks_per_beat: u8, swing: f64) -> f64 {
+    if !swings(ticks_per_beat) || tick >= ticks_per_beat {
+        return 1.0;
+    }
+    if tick % 2 == 0 {
+        2.0 * swing
+    } else {
+        2.0 * (1.0 - swing)
+    }
+}
+
+/// Where tick `tick` falls within its beat, from 0 to 1, under `swing`.
+pub fn swung_position(tick: u8, ticks_per_beat: u8, swing: f64) -> f64 {
+    let straight = tick as f64 / ticks_per_beat as f64;
+    if swings(ticks_per_beat) && tick % 2 == 1 {
+        straight + (2.0 * swing - 1.0) / ticks_per_beat as f64
+    } else {
+        straight
+    }
+}
+
 pub struct RampCfg {
     pub from_bpm: u16,
     pub to_bpm: u16,
@@ -59,3 +157,108 @@ pub fn parse_ramp_pattern(
         duration_ms,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn session_length_needs_a_unit_and_a_positive_value() {
+        assert_eq!(parse_session_length("20m"), Ok(1_200_000));
+        assert_eq!(parse_session_length("90s"), Ok(90_000));
+        assert_eq!(parse_session_length("1.5h"), Ok(5_400_000));
+        assert_eq!(parse_session_length("500ms"), Ok(500));
+        assert!(parse_session_length("20").is_err());
+        assert!(parse_session_length("0m").is_err());
+        assert!(parse_session_length("0s").is_err());
+        assert!(parse_session_length("-5m").is_err());
+        assert!(parse_session_length("-1h").is_err());
+        assert!(parse_session_length("xh").is_err());
+    }
+
+    #[test]
+    fn hours_are_only_for_session_lengths() {
+        assert!(parse_duration_ms("1h").is_err());
+        assert_eq!(parse_duration_ms("2m"), Ok(120_000));
+    }
+
+    #[test]
+    fn blocks_split_play_and_break() {
+        assert_eq!(parse_blocks("25m/5m"), Ok((1_500_000, 300_000)));
+        assert_eq!(parse_blocks("90s / 0s"), Ok((90_000, 0)));
+        assert!(parse_blocks("25m").is_err());
+        assert!(parse_blocks("0m/5m").is_err());
+        assert!(parse_blocks("25m/x").is_err());
+    }
+
+    #[test]
+    fn percentages_with_or_without_the_sign() {
+        assert_eq!(parse_percent("70%"), Ok(0.7));
+        assert_eq!(parse_percent(" 70 "), Ok(0.7));
+        assert_eq!(parse_percent("70 %"), Ok(0.7));
+        assert!(parse_percent("seventy").is_err());
+    }
+
+    #[test]
+    fn speed_and_swing_stay_in_range() {
+        assert_eq!(parse_speed("25%"), Ok(0.25));
+        assert_eq!(parse_speed("150"), Ok(1.5));
+        assert!(parse_speed("20%").is_err());
+        assert!(parse_speed("151%").is_err());
+        assert_eq!(parse_swing("50%"), Ok(0.5));
+        assert_eq!(parse_swing("75%"), Ok(0.75));
+        assert!(parse_swing("49%").is_err());
+        assert!(parse_swing("80%").is_err());
+    }
+
+    #[test]
+    fn swung_pairs_add_up_to_the_straight_beat() {
+        for swing in [0.5, 2.0 / 3.0, 0.75] {
+            for ticks in [2, 4] {
+                for pair in (0..ticks).step_by(2) {
+                    let sum =
+                        swung_length(pair, ticks, swing) + swung_length(pair + 1, ticks, swing);
+                    assert!(close(sum, 2.0), "{} at {} ticks: {}", swing, ticks, sum);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn swung_lengths_at_straight_triplet_and_hard_swing() {
+        assert!(close(swung_length(0, 2, 0.5), 1.0));
+        assert!(close(swung_length(1, 2, 0.5), 1.0));
+        assert!(close(swung_length(0, 2, 2.0 / 3.0), 4.0 / 3.0));
+        assert!(close(swung_length(1, 2, 2.0 / 3.0), 2.0 / 3.0));
+        assert!(close(swung_length(0, 4, 0.75), 1.5));
+        assert!(close(swung_length(1, 4, 0.75), 0.5));
+        // Triplets and quarters are never swung.
+        assert!(close(swung_length(0, 3, 0.75), 1.0));
+        assert!(close(swung_length(0, 1, 0.75), 1.0));
+    }
+
+    #[test]
+    fn swung_positions_follow_the_lengths() {
+        assert!(close(swung_position(1, 2, 0.5), 0.5));
+        assert!(close(swung_position(1, 2, 2.0 / 3.0), 2.0 / 3.0));
+        assert!(close(swung_position(1, 2, 0.75), 0.75));
+        for swing in [0.5, 2.0 / 3.0, 0.75] {
+            let mut at = 0.0;
+            for tick in 0..4 {
+                assert!(
+                    close(swung_position(tick, 4, swing), at / 4.0),
+                    "{} tick {}",
+                    swing,
+                    tick
+                );
+                at += swung_length(tick, 4, swing);
+            }
+            assert!(close(at, 4.0));
+        }
+        assert!(close(swung_position(1, 3, 0.75), 1.0 / 3.0));
+    }
+}
@@ -0,0 +1,463 @@
+use std::io::Write;
+use std::path::Path;
+use std::time::Instant;
+
+/// Width of one histogram bucket in milliseconds.
+const BUCKET_MS: f64 = 10.0;
+/// Buckets on each side of zero; anything further lands in the outer bucket.
+const BUCKETS_PER_SIDE: i32 = 5;
+const HISTOGRAM_WIDTH: usize = 40;
+
+/// Signed offset in ms of `at` from the nearest of two neighbouring ticks.
+/// Negative means early, positive means late.
+pub fn grid_offset_ms(at: Instant, prev_tick: Instant, next_tick: Instant) -> f64 {
+    if at >= next_tick {
+        return (at - next_tick).as_secs_f64() * 1000.0;
+    }
+    if at <= prev_tick {
+        return -((prev_tick - at).as_secs_f64() * 1000.0);
+    }
+    let after_prev = (at - prev_tick).as_secs_f64() * 1000.0;
+    let before_next = (next_tick - at).as_secs_f64() * 1000.0;
+    if after_prev <= before_next {
+        after_prev
+    } else {
+        -before_next
+    }
+}
+
+/// How far `at` is from the nearest of `ticks`, given in order.
+pub fn nearest_offset_ms(at: Instant, ticks: impl IntoIterator<Item = Instant>) -> f64 {
+    let mut prev = None;
+    for tick in ticks {
+        if tick > at {
+            return grid_offset_ms(at, prev.unwrap_or(tick), tick);
+        }
+        prev = Some(tick);
+    }
+    prev.map_or(0.0, |tick| grid_offset_ms(at, tick, tick))
+}
+
+/// A trainer run counts as clean with at least this many taps, averaging
+/// no further than `CLEAN_MAX_MEAN_MS` off the grid with a spread of at
+/// most `CLEAN_MAX_STDEV_MS`.
+const CLEAN_MIN_TAPS: usize = 16;
+const CLEAN_MAX_MEAN_MS: f64 = 10.0;
+const CLEAN_MAX_STDEV_MS: f64 = 15.0;
+
+/// Tap offsets collected by the tap-along trainer.
+#[derive(Default)]
+pub struct TimingLog {
+    offsets_ms: Vec<f64>,
+}
+
+impl TimingLog {
+    pub fn record(&mut self, offset_ms: f64) {
+        self.offsets_ms.push(offset_ms);
+    }
+
+    pub fn last(&self) -> Option<f64> {
+        self.offsets_ms.last().copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.offsets_ms.len()
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        if self.offsets_ms.is_empty() {
+            return None;
+        }
+        Some(self.offsets_ms.iter().sum::<f64>() / self.offsets_ms.len() as f64)
+    }
+
+    pub fn median(&self) -> Option<f64> {
+        if self.offsets_ms.is_empty() {
+            return None;
+        }
+        let mut sorted = self.offsets_ms.clone();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        let mid = sorted.len() / 2;
+        if sorted.len() % 2 == 0 {
+            Some((sorted[mid - 1] + sorted[mid]) / 2.0)
+        } else {
+            Some(sorted[mid])
+        }
+    }
+
+    pub fn stdev(&self) -> Option<f64> {
+        let mean = self.mean()?;
+        let var = self
+            .offsets_ms
+            .iter()
+            .map(|v| (v - mean).powi(2))
+            .sum::<f64>()
+            / self.offsets_ms.len() as f64;
+        Some(var.sqrt())
+    }
+
+    /// Whether the taps were steady and centered enough for the tempo to
+    /// count as played cleanly.
+    pub fn is_clean(&self) -> bool {
+        match (self.mean(), self.stdev()) {
+            (Some(mean), Some(stdev)) => {
+                self.len() >= CLEAN_MIN_TAPS
+                    && mean.abs() <= CLEAN_MAX_MEAN_MS
+                    && stdev <= CLEAN_MAX_STDEV_MS
+            }
+            _ => false,
+        }
+    }
+
+    /// Text histogram, one line per bucket from early to late.
+    pub fn histogram(&self) -> Vec<String> {
+        let mut counts = vec![0usize; (BUCKETS_PER_SIDE * 2 + 1) as usize];
+        for &v in &self.offsets_ms {
+            let idx = ((v / BUCKET_MS).round() as i32).clamp(-BUCKETS_PER_SIDE, BUCKETS_PER_SIDE);
+            counts[(idx + BUCKETS_PER_SIDE) as usize] += 1;
+        }
+        let max = counts.iter().copied().max().unwrap_or(0).max(1);
+        counts
+            .iter()
+            .enumerate()
+            .map(|(i, &count)| {
+                let center = (i as i32 - BUCKETS_PER_SIDE) as f64 * BUCKET_MS;
+                let label = if i == 0 {
+                    format!("<={:+.0}", center)
+                } else if i as i32 == BUCKETS_PER_SIDE * 2 {
+                    format!(">={:+.0}", center)
+                } else {
+                    format!("{:+.0}", center)
+                };
+                let bar = "█".repeat(count * HISTOGRAM_WIDTH / max);
+                format!("{:>6} ms | {} {}", label, bar, count)
+            })
+            .collect()
+    }
+
+    /// Summary with histogram; `what` names the entries, e.g. "Taps".
+    pub fn report(&self, what: &str) -> String {
+        let (Some(mean), Some(stdev)) = (self.mean(), self.stdev()) else {
+            return format!("No {} recorded.\n", what.to_lowercase());
+        };
+        let tendency = if mean < -5.0 {
+            "rushing"
+        } else if mean > 5.0 {
+            "dragging"
+        } else {
+            "centered"
+        };
+        let mut out = format!(
+            "{}: {}   mean: {:+.1} ms ({})   stdev: {:.1} ms\n",
+            what,
+            self.len(),
+            mean,
+            tendency,
+            stdev
+        );
+        for line in self.histogram() {
+            out.push_str(&line);
+            out.push('\n');
+        }
+        out
+    }
+
+    pub fn write_csv(&self, path: &Path) -> std::io::Result<()> {
+        let mut file = std::fs::File::create(path)?;
+        writeln!(file, "tap,offset_ms")?;
+        for (i, v) in self.offsets_ms.iter().enumerate() {
+            writeln!(file, "{},{:.3}", i + 1, v)?;
+        }
+        Ok(())
+    }
+}
+
+/// Taps from the silent part of a tempo retention test, measured against
+/// the grid the click would have kept playing.
+pub struct RetentionLog {
+    pub click_bars: u32,
+    beat_secs: f64,
+    beats_per_bar: u32,
+    start: Option<Instant>,
+    taps: Vec<Instant>,
+}
+
+impl RetentionLog {
+    pub fn new(click_bars: u32) -> Self {
+        RetentionLog {
+            click_bars,
+            beat_secs: 0.5,
+            beats_per_bar: 4,
+            start: None,
+            taps: Vec::new(),
+        }
+    }
+
+    /// Marks the first silent downbeat; later calls are ignored. `bpm` is
+    /// the tempo as heard, after any speed change.
+    pub fn begin(&mut self, at: Instant, bpm: f64, beats_per_bar: u8) {
+        if self.start.is_none() {
+            self.start = Some(at);
+            self.beat_secs = 60.0 / bpm.max(1.0);
+            self.beats_per_bar = beats_per_bar.max(1) as u32;
+        }
+    }
+
+    pub fn is_silent(&self) -> bool {
+        self.start.is_some()
+    }
+
+    pub fn record(&mut self, at: Instant) {
+        if self.start.is_some() {
+            self.taps.push(at);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.taps.len()
+    }
+
+    pub fn report(&self) -> String {
+        let Some(start) = self.start else {
+            return "The silent section was never reached.".to_string();
+        };
+        if self.taps.len() < 3 {
+            return "Not enough taps in the silent section.".to_string();
+        }
+        let period = self.beat_secs;
+        // Assign taps to beats one after another so a drifting player is not
+        // snapped onto a neighbouring beat once they are half a beat off.
+        let mut beats: Vec<f64> = Vec::with_capacity(self.taps.len());
+        let mut times: Vec<f64> = Vec::with_capacity(self.taps.len());
+        for (i, &tap) in self.taps.iter().enumerate() {
+            let t = tap.saturating_duration_since(start).as_secs_f64();
+            let k = if i == 0 {
+                (t / period).round()
+            } else {
+                let step = ((t - times[i - 1]) / period).round().max(1.0);
+                beats[i - 1] + step
+            };
+            beats.push(k);
+            times.push(t);
+        }
+        let Some((player_period, _)) = linear_fit(&beats, &times) else {
+            return "Not enough distinct beats in the silent section.".to_string();
+        };
+        let target_bpm = 60.0 / period;
+        let player_bpm = 60.0 / player_period;
+        let offsets: Vec<f64> = beats
+            .iter()
+            .zip(&times)
+            .map(|(k, t)| (t - k * period) * 1000.0)
+            .collect();
+        let per_bar_ms = (player_period - period) * 1000.0 * self.beats_per_bar as f64;
+        let last = offsets.last().copied().unwrap_or(0.0);
+        let max = offsets.iter().fold(0.0f64, |m, v| m.max(v.abs()));
+
+        let mut out = format!(
+            "Silent section: {} taps over {} beats\n",
+            self.taps.len(),
+            beats.last().copied().unwrap_or(0.0) - beats[0] + 1.0
+        );
+        out.push_str(&format!(
+            "Tempo: {:.1} BPM (target {:.0}, drift {:+.1} BPM)\n",
+            player_bpm,
+            target_bpm,
+            player_bpm - target_bpm
+        ));
+        out.push_str(&format!(
+            "Phase: {:+.1} ms per bar, ended {:+.1} ms off the grid (max {:.1} ms)\n",
+            per_bar_ms, last, max
+        ));
+        let mut bar = None;
+        let mut sum = 0.0;
+        let mut count = 0;
+        for (k, off) in beats.iter().zip(&offsets) {
+            let b = (*k as i64).div_euclid(self.beats_per_bar as i64) + 1;
+            if bar.is_some_and(|prev| prev != b) {
+                out.push_str(&format!(
+                    "  bar {:>3}: {:+7.1} ms\n",
+                    bar.unwrap_or(0),
+                    sum / count as f64
+                ));
+                sum = 0.0;
+                count = 0;
+            }
+            bar = Some(b);
+            sum += off;
+            count += 1;
+        }
+        if let Some(b) = bar {
+            out.push_str(&format!("  bar {:>3}: {:+7.1} ms\n", b, sum / count as f64));
+        }
+        out
+    }
+}
+
+/// Least-squares line through the points, as (slope, intercept).
+pub fn linear_fit(xs: &[f64], ys: &[f64]) -> Option<(f64, f64)> {
+    let n = xs.len() as f64;
+    if xs.len() < 2 {
+        return None;
+    }
+    let mean_x = xs.iter().sum::<f64>() / n;
+    let mean_y = ys.iter().sum::<f64>() / n;
+    let sxx: f64 = xs.iter().map(|x| (x - mean_x).powi(2)).sum();
+    if sxx == 0.0 {
+        return None;
+    }
+    let sxy: f64 = xs
+        .iter()
+        .zip(ys)
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum();
+    let slope = sxy / sxx;
+    Some((slope, mean_y - slope * mean_x))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn ms(start: Instant, ms: f64) -> Instant {
+        start + Duration::from_secs_f64(ms / 1000.0)
+    }
+
+    fn log(offsets: &[f64]) -> TimingLog {
+        let mut log = TimingLog::default();
+        for &v in offsets {
+            log.record(v);
+        }
+        log
+    }
+
+    #[test]
+    fn offsets_wrap_at_half_a_tick() {
+        let t = Instant::now();
+        let (prev, next) = (ms(t, 1000.0), ms(t, 1500.0));
+        assert!((grid_offset_ms(ms(t, 1010.0), prev, next) - 10.0).abs() < 1e-6);
+        assert!((grid_offset_ms(ms(t, 1490.0), prev, next) + 10.0).abs() < 1e-6);
+        // Exactly halfway counts as late for the earlier tick.
+        assert!((grid_offset_ms(ms(t, 1250.0), prev, next) - 250.0).abs() < 1e-6);
+        assert!((grid_offset_ms(ms(t, 1251.0), prev, next) + 249.0).abs() < 1e-6);
+        // Outside the pair, the nearer tick still wins.
+        assert!((grid_offset_ms(ms(t, 990.0), prev, next) + 10.0).abs() < 1e-6);
+        assert!((grid_offset_ms(ms(t, 1520.0), prev, next) - 20.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn nearest_offset_picks_the_surrounding_pair() {
+        let t = Instant::now();
+        let ticks = [0.0, 500.0, 1000.0, 1500.0].map(|v| ms(t, v));
+        assert!((nearest_offset_ms(ms(t, 1020.0), ticks) - 20.0).abs() < 1e-6);
+        assert!((nearest_offset_ms(ms(t, 980.0), ticks) + 20.0).abs() < 1e-6);
+        assert!((nearest_offset_ms(ms(t, 1600.0), ticks) - 100.0).abs() < 1e-6);
+        assert!((nearest_offset_ms(ms(t, 0.0), [ms(t, 30.0)]) + 30.0).abs() < 1e-6);
+        assert_eq!(nearest_offset_ms(t, []), 0.0);
+    }
+
+    #[test]
+    fn statistics_of_the_offsets() {
+        let log = log(&[-10.0, 0.0, 10.0, 20.0]);
+        assert_eq!(log.mean(), Some(5.0));
+        assert_eq!(log.median(), Some(5.0));
+        assert!((log.stdev().unwrap() - 125f64.sqrt()).abs() < 1e-9);
+        assert_eq!(TimingLog::default().mean(), None);
+        assert_eq!(TimingLog::default().stdev(), None);
+    }
+
+    #[test]
+    fn a_clean_take_needs_enough_steady_centered_taps() {
+        let steady = |n: usize, center: f64, spread: f64| {
+            let offsets: Vec<f64> = (0..n)
+                .map(|i| center + if i % 2 == 0 { spread } else { -spread })
+                .collect();
+            log(&offsets).is_clean()
+        };
+        assert!(steady(CLEAN_MIN_TAPS, 0.0, 5.0));
+        assert!(!steady(CLEAN_MIN_TAPS - 1, 0.0, 5.0));
+        assert!(steady(CLEAN_MIN_TAPS, CLEAN_MAX_MEAN_MS, 0.0));
+        assert!(!steady(CLEAN_MIN_TAPS, CLEAN_MAX_MEAN_MS + 0.5, 0.0));
+        assert!(!steady(CLEAN_MIN_TAPS, -CLEAN_MAX_MEAN_MS - 0.5, 0.0));
+        assert!(steady(CLEAN_MIN_TAPS, 0.0, CLEAN_MAX_STDEV_MS));
+        assert!(!steady(CLEAN_MIN_TAPS, 0.0, CLEAN_MAX_STDEV_MS + 0.5));
+    }
+
+    #[test]
+    fn histogram_buckets_clamp_at_the_ends() {
+        let lines = log(&[-80.0, -4.0, 0.0, 4.0, 12.0, 300.0]).histogram();
+        assert_eq!(lines.len(), (BUCKETS_PER_SIDE * 2 + 1) as usize);
+        let count = |line: &str| line.rsplit(' ').next().unwrap().to_string();
+        assert!(lines[0].starts_with(" <=-50 ms |"));
+        assert_eq!(count(&lines[0]), "1");
+        assert_eq!(count(&lines[BUCKETS_PER_SIDE as usize]), "3");
+        assert_eq!(count(&lines[BUCKETS_PER_SIDE as usize + 1]), "1");
+        assert_eq!(count(lines.last().unwrap()), "1");
+    }
+
+    #[test]
+    fn linear_fit_recovers_a_line() {
+        let xs = [0.0, 1.0, 2.0, 3.0];
+        let ys: Vec<f64> = xs.iter().map(|x| 0.49 * x + 0.2).collect();
+        let (slope, intercept) = linear_fit(&xs, &ys).unwrap();
+        assert!((slope - 0.49).abs() < 1e-12);
+        assert!((intercept - 0.2).abs() < 1e-12);
+    }
+
+    #[test]
+    fn linear_fit_needs_two_distinct_xs() {
+        assert_eq!(linear_fit(&[1.0], &[2.0]), None);
+        assert_eq!(linear_fit(&[], &[]), None);
+        assert_eq!(linear_fit(&[3.0, 3.0, 3.0], &[1.0, 2.0, 3.0]), None);
+    }
+
+    /// A retention run at 120 BPM in 4/4 with taps at `times` seconds
+    /// after the first silent downbeat.
+    fn retention(times: &[f64]) -> String {
+        let start = Instant::now();
+        let mut log = RetentionLog::new(2);
+        log.begin(start, 120.0, 4);
+        for &t in times {
+            log.record(ms(start, t * 1000.0));
+        }
+        log.report()
+    }
+
+    #[test]
+    fn retention_reports_drift_and_phase() {
+        // Rushing: a beat every 0.49 s instead of 0.5 s.
+        let times: Vec<f64> = (0..8).map(|i| i as f64 * 0.49).collect();
+        let report = retention(&times);
+        assert!(report.contains("8 taps over 8 beats"), "{}", report);
+        assert!(report.contains("Tempo: 122.4 BPM (target 120, drift +2.4 BPM)"));
+        assert!(report.contains("Phase: -40.0 ms per bar, ended -70.0 ms"));
+        assert!(report.contains("bar   1:   -15.0 ms"));
+        assert!(report.contains("bar   2:   -55.0 ms"));
+    }
+
+    #[test]
+    fn retention_follows_a_drifting_player_across_a_skipped_beat() {
+        // Dragging by 40 ms a beat with beat 3 not tapped; by the end the
+        // taps are more than half a beat late but still on their own beats.
+        let times: Vec<f64> = [0, 1, 2, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13]
+            .iter()
+            .map(|&k| k as f64 * 0.54)
+            .collect();
+        let report = retention(&times);
+        assert!(report.contains("13 taps over 14 beats"), "{}", report);
+        assert!(report.contains("drift -8.9 BPM"), "{}", report);
+    }
+
+    #[test]
+    fn retention_needs_a_silent_section_and_taps() {
+        assert_eq!(
+            RetentionLog::new(2).report(),
+            "The silent section was never reached."
+        );
+        assert_eq!(
+            retention(&[0.0, 0.5]),
+            "Not enough taps in the silent section."
+        );
+    }
+}
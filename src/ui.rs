@@ -1,26 +1,61 @@
 use crossterm::{
     QueueableCommand, cursor,
-    style::{Attribute, Color, ResetColor, SetAttribute, SetForegroundColor},
+    style::{Attribute, Color, ResetColor, SetAttribute, SetBackgroundColor, SetForegroundColor},
     terminal::{Clear, ClearType},
 };
 use std::io::Write;
 use unicode_width::UnicodeWidthStr;
 
-pub fn render_ui(
-    out: &mut std::io::Stdout,
-    width: u16,
-    height: u16,
-    bpm: u16,
-    bar_beats: u8,
-    denom: u8,
-    ticks_per_beat: u8,
-    beat_in_bar: u8,
-    tick_in_beat: u8,
-    playing: bool,
-    accent: bool,
-    show_help: bool,
-) {
-    fn render_tokens(out: &mut std::io::Stdout, text: &str) {
+use crate::tempo::swung_position;
+
+/// Everything `render_ui` needs to draw one frame.
+#[derive(Clone)]
+pub struct UiState {
+    pub bpm: u16,
+    pub bar_beats: u8,
+    pub denom: u8,
+    pub ticks_per_beat: u8,
+    pub beat_in_bar: u8,
+    pub tick_in_beat: u8,
+    pub playing: bool,
+    pub accent: bool,
+    pub show_help: bool,
+    /// Extra HUD segments appended after the run state (e.g. a tap estimate).
+    pub hud_extra: Vec<String>,
+    /// Trainer mode: <Space> taps along instead of pausing.
+    pub training: bool,
+    /// Offset of the latest trainer tap from the grid, negative is early.
+    pub tap_offset_ms: Option<f64>,
+    /// Retention test silent section: hide the moving tick.
+    pub blind: bool,
+    /// Light up the beat bar as a visual click while audio is down.
+    pub flash: bool,
+    /// Beats left in the count-in, shown in big digits in place of the bar.
+    pub count_in: Option<u32>,
+    /// Swing ratio, placing the ticks of a pair unevenly on the bar.
+    pub swing: f64,
+}
+
+pub fn render_ui(out: &mut impl Write, width: u16, height: u16, state: &UiState) {
+    let UiState {
+        bpm,
+        bar_beats,
+        denom,
+        ticks_per_beat,
+        beat_in_bar,
+        tick_in_beat,
+        playing,
+        accent,
+        show_help,
+        ref hud_extra,
+        training,
+        tap_offset_ms,
+        blind,
+        flash,
+        count_in,
+        swing,
+    } = *state;
+    fn render_tokens(out: &mut impl Write, text: &str) {
         let mut in_token = false;
         for ch in text.chars() {
             match ch {
@@ -44,15 +79,29 @@ pub fn render_ui(
         }
     }
     let total_ticks = (bar_beats as u32) * (ticks_per_beat as u32);
-    let current_tick_index =
-        ((beat_in_bar as u32 - 1) * ticks_per_beat as u32) + tick_in_beat as u32;
+    // Column of a tick, with the subdivisions placed by the swing.
+    let tick_x = |beat: u32, tick: u8| -> usize {
+        let at = beat as f64 + swung_position(tick, ticks_per_beat, swing);
+        ((at / bar_beats as f64 * width as f64 + 1e-6) as usize)
+            .min((width as usize).saturating_sub(1))
+    };
 
     let title = "Metronome";
-    let hud_state = if playing { "RUN" } else { "PAUSE" };
-    let hud_text = format!(
+    let hud_state = if count_in.is_some() {
+        "COUNT-IN"
+    } else if playing {
+        "RUN"
+    } else {
+        "PAUSE"
+    };
+    let mut hud_text = format!(
         "{:>3} BPM  |  {}/{}  |  sub {}  |  {}",
         bpm, bar_beats, denom, ticks_per_beat, hud_state
     );
+    for extra in hud_extra {
+        hud_text.push_str("  |  ");
+        hud_text.push_str(extra);
+    }
 
     let _ = out.queue(Clear(ClearType::All));
     let _ = out.queue(cursor::MoveTo(0, 0));
@@ -72,63 +121,89 @@ pub fn render_ui(
     let _ = out.queue(ResetColor);
     let _ = out.queue(SetAttribute(Attribute::Reset));
 
+    if let Some(offset) = tap_offset_ms {
+        render_offset_meter(out, width, offset);
+    }
+
     let bar_top = 2u16;
     let bar_bottom = height.saturating_sub(3).max(bar_top);
-    for row in bar_top..=bar_bottom {
-        let _ = out.queue(cursor::MoveTo(0, row));
-        let mut bar: Vec<char> = vec![' '; width as usize];
-        if width > 0 && total_ticks > 0 {
+    if let Some(beats) = count_in {
+        render_countdown(out, width, bar_top, bar_bottom, beats);
+    } else {
+        for row in bar_top..=bar_bottom {
+            let _ = out.queue(cursor::MoveTo(0, row));
+            let mut bar: Vec<char> = vec![' '; width as usize];
+            if width > 0 && total_ticks > 0 {
+                for b in 0..=bar_beats as u32 {
+                    let pos = ((b * ticks_per_beat as u32) * width as u32) / total_ticks;
+                    let idx = pos.min(width as u32 - 1) as usize;
+                    bar[idx] = '|';
+                }
+                for b in 0..bar_beats as u32 {
+                    let left = ((b * ticks_per_beat as u32) * width as u32) / total_ticks;
+                    let right = (((b + 1) * ticks_per_beat as u32) * width as u32) / total_ticks;
+                    let left_i = left.min(width as u32 - 1) as usize;
+                    let right_i = right.min(width as u32 - 1) as usize;
+                    let fill_char = if !blind && (b + 1) == beat_in_bar as u32 {
+                        '='
+                    } else {
+                        '-'
+                    };
+                    for (i, c) in bar.iter_mut().enumerate() {
+                        if i > left_i && i < right_i {
+                            *c = fill_char;
+                        }
+                    }
+                    for tick in 1..ticks_per_beat {
+                        bar[tick_x(b, tick)] = '·';
+                    }
+                }
+                if !blind {
+                    bar[tick_x(beat_in_bar as u32 - 1, tick_in_beat)] = '●';
+                }
+            }
+            let line: String = bar.into_iter().collect();
+            if flash {
+                let _ = out.queue(SetBackgroundColor(if accent && beat_in_bar == 1 {
+                    Color::Yellow
+                } else {
+                    Color::Grey
+                }));
+            }
+            let _ = out.queue(SetForegroundColor(Color::DarkBlue));
+            let _ = write!(out, "{}", line);
+            let _ = out.queue(cursor::MoveTo(0, row));
+            let _ = out.queue(SetForegroundColor(Color::Blue));
             for b in 0..=bar_beats as u32 {
                 let pos = ((b * ticks_per_beat as u32) * width as u32) / total_ticks;
-                let idx = pos.min(width as u32 - 1) as usize;
-                bar[idx] = '|';
+                let idx = pos.min(width as u32 - 1) as u16;
+                let _ = out.queue(cursor::MoveTo(idx, row));
+                let _ = write!(out, "|");
             }
-            for b in 0..bar_beats as u32 {
-                let left = ((b * ticks_per_beat as u32) * width as u32) / total_ticks;
-                let right = (((b + 1) * ticks_per_beat as u32) * width as u32) / total_ticks;
-                let left_i = left.min(width as u32 - 1) as usize;
-                let right_i = right.min(width as u32 - 1) as usize;
-                let fill_char = if (b + 1) == beat_in_bar as u32 {
-                    '='
-                } else {
-                    '-'
-                };
-                for i in left_i.saturating_add(1)..right_i {
-                    bar[i] = fill_char;
-                }
+            if blind {
+                let _ = out.queue(ResetColor);
+                continue;
             }
-            let tick_pos = (current_tick_index * width as u32) / total_ticks;
-            let tick_idx = tick_pos.min(width as u32 - 1) as usize;
-            bar[tick_idx] = '●';
-        }
-        let line: String = bar.into_iter().collect();
-        let _ = out.queue(SetForegroundColor(Color::DarkBlue));
-        let _ = write!(out, "{}", line);
-        let _ = out.queue(cursor::MoveTo(0, row));
-        let _ = out.queue(SetForegroundColor(Color::Blue));
-        for b in 0..=bar_beats as u32 {
-            let pos = ((b * ticks_per_beat as u32) * width as u32) / total_ticks;
-            let idx = pos.min(width as u32 - 1) as u16;
-            let _ = out.queue(cursor::MoveTo(idx, row));
-            let _ = write!(out, "|");
+            let x = tick_x(beat_in_bar as u32 - 1, tick_in_beat) as u16;
+            let _ = out.queue(cursor::MoveTo(x, row));
+            let _ = out.queue(SetForegroundColor(
+                if accent && tick_in_beat == 0 && beat_in_bar == 1 {
+                    Color::Yellow
+                } else {
+                    Color::Cyan
+                },
+            ));
+            let _ = write!(out, "●");
+            let _ = out.queue(ResetColor);
         }
-        let tick_pos = (current_tick_index * width as u32) / total_ticks;
-        let tick_x = tick_pos.min(width as u32 - 1) as u16;
-        let _ = out.queue(cursor::MoveTo(tick_x, row));
-        let _ = out.queue(SetForegroundColor(
-            if accent && tick_in_beat == 0 && beat_in_bar == 1 {
-                Color::Yellow
-            } else {
-                Color::Cyan
-            },
-        ));
-        let _ = write!(out, "●");
-        let _ = out.queue(ResetColor);
     }
 
-    let left_help =
-        "<Space>: Play/Pause   <q>/<Esc>: Quit   <s>: Subdivision   <Tab>: Signature   <h>: Help";
-    let right_help = "<↑>/<↓>: ±1   <←>/<→>: ±5";
+    let left_help = if training {
+        "<Space>: Tap along   <p>: Pause   <q>/<Esc>: Finish   <s>: Subdivision   <Tab>: Signature   <h>: Help"
+    } else {
+        "<Space>: Play/Pause   <q>/<Esc>: Quit   <s>: Subdivision   <Tab>: Signature   <h>: Help"
+    };
+    let right_help = "<t>: Tap   <↑>/<↓>: ±1   <←>/<→>: ±5";
     let help_y = height.saturating_sub(1);
     let _ = out.queue(cursor::MoveTo(0, help_y));
     let _ = out.queue(SetForegroundColor(Color::DarkGrey));
@@ -166,8 +241,8 @@ pub fn render_ui(
         }
     }
 
-    if show_help && height > 4 {
-        let box_top = height.saturating_sub(5);
+    if show_help && height > 7 {
+        let box_top = height.saturating_sub(8);
         let _ = out.queue(cursor::MoveTo(0, box_top));
         let _ = out.queue(SetForegroundColor(Color::DarkGrey));
         let _ = out.queue(Clear(ClearType::FromCursorDown));
@@ -176,6 +251,9 @@ pub fn render_ui(
             "  <Space> Play/Pause   <q>/<Esc> Quit",
             "  <s> Subdivision   <Tab> Signature",
             "  <↑>/<↓> ±1   <←>/<→> ±5",
+            "  <t> Tap tempo   <Enter> Apply tap   <Backspace> Clear taps",
+            "  <[>/<]> Speed ±5%   <,>/<.> Swing ±2%",
+            "  <v> Next sound (plays it)   <r> Reload voices from config",
         ];
         for (i, l) in lines.iter().enumerate() {
             let _ = out.queue(cursor::MoveTo(0, box_top + i as u16));
@@ -186,3 +264,90 @@ pub fn render_ui(
     let _ = out.queue(ResetColor);
     let _ = out.flush();
 }
+
+/// 3×5 digits, drawn two cells per pixel so they come out square.
+const DIGITS: [[&str; 5]; 10] = [
+    ["███", "█ █", "█ █", "█ █", "███"],
+    [" █ ", "██ ", " █ ", " █ ", "███"],
+    ["███", "  █", "███", "█  ", "███"],
+    ["███", "  █", "███", "  █", "███"],
+    ["█ █", "█ █", "███", "  █", "  █"],
+    ["███", "█  ", "███", "  █", "███"],
+    ["███", "█  ", "███", "█ █", "███"],
+    ["███", "  █", "  █", "  █", "  █"],
+    ["███", "█ █", "███", "█ █", "███"],
+    ["███", "█ █", "███", "  █", "███"],
+];
+
+/// The count-in's beats left, centered between `top` and `bottom`; plain
+/// digits when there is no room for big ones.
+fn render_countdown(out: &mut impl Write, width: u16, top: u16, bottom: u16, beats: u32) {
+    let text = beats.to_string();
+    let rows: Vec<String> = (0..5)
+        .map(|row| {
+            text.bytes()
+                .map(|d| {
+                    DIGITS[(d - b'0') as usize][row]
+                        .chars()
+                        .flat_map(|c| [c, c])
+                        .collect::<String>()
+                })
+                .collect::<Vec<_>>()
+                .join("  ")
+        })
+        .collect();
+    let big_w = UnicodeWidthStr::width(rows[0].as_str()) as u16;
+    let _ = out.queue(SetAttribute(Attribute::Bold));
+    let _ = out.queue(SetForegroundColor(Color::Yellow));
+    if bottom - top + 1 >= 5 && width >= big_w {
+        let y = top + (bottom - top + 1 - 5) / 2;
+        let x = (width - big_w) / 2;
+        for (i, line) in rows.iter().enumerate() {
+            let _ = out.queue(cursor::MoveTo(x, y + i as u16));
+            let _ = write!(out, "{}", line);
+        }
+    } else {
+        let _ = out.queue(cursor::MoveTo(
+            width.saturating_sub(text.len() as u16) / 2,
+            top,
+        ));
+        let _ = write!(out, "{}", text);
+    }
+    let _ = out.queue(ResetColor);
+    let _ = out.queue(SetAttribute(Attribute::Reset));
+}
+
+/// Early/late meter on the row between the HUD and the bar, ±50 ms full scale.
+fn render_offset_meter(out: &mut impl Write, width: u16, offset_ms: f64) {
+    const CELLS: i32 = 41;
+    const RANGE_MS: f64 = 50.0;
+    let half = CELLS / 2;
+    let pos = ((offset_ms / RANGE_MS) * half as f64)
+        .round()
+        .clamp(-half as f64, half as f64) as i32;
+    let scale: String = (-half..=half)
+        .map(|i| {
+            if i == pos {
+                '●'
+            } else if i == 0 {
+                '|'
+            } else {
+                '─'
+            }
+        })
+        .collect();
+    let text = format!("early {} late  {:+6.1} ms", scale, offset_ms);
+    let text_w = UnicodeWidthStr::width(text.as_str()) as u16;
+    let x = width.saturating_sub(text_w) / 2;
+    let color = if offset_ms.abs() < 10.0 {
+        Color::Green
+    } else if offset_ms.abs() < 25.0 {
+        Color::Yellow
+    } else {
+        Color::Red
+    };
+    let _ = out.queue(cursor::MoveTo(x, 1));
+    let _ = out.queue(SetForegroundColor(color));
+    let _ = write!(out, "{}", text);
+    let _ = out.queue(ResetColor);
+}